@@ -0,0 +1,174 @@
+//! Computer players. A `Bot` only looks at the simulation and produces `Input`, like a player at
+//! the keyboard, so it takes part in a match as an ordinary local player of its session.
+
+use sim::{Fixed, Input, Rng, Sim, PLAYER_SIZE};
+use std::collections::VecDeque;
+
+/// Bots only shoot at players closer than this many pixels, well within pistol range.
+const SHOOTING_RANGE: i32 = 160;
+/// Bullets appear in front of the shooter, so bots back off from players closer than this.
+const MIN_RANGE: i32 = 3 * PLAYER_SIZE;
+/// Bots notice bullets flying at them from this many pixels away.
+const DODGE_DISTANCE: i32 = 48;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "easy" => Some(Difficulty::Easy),
+            "normal" => Some(Difficulty::Normal),
+            "hard" => Some(Difficulty::Hard),
+            _ => None,
+        }
+    }
+
+    /// Frames between the bot seeing something and its reaction.
+    fn reaction_frames(self) -> usize {
+        match self {
+            Difficulty::Easy => 20,
+            Difficulty::Normal => 10,
+            Difficulty::Hard => 3,
+        }
+    }
+
+    /// How far above or below the bot, in pixels, a target may be for it to shoot. Bullets
+    /// only hit within half a player's height.
+    fn aim_tolerance(self) -> i32 {
+        match self {
+            Difficulty::Easy => 12,
+            Difficulty::Normal => 6,
+            Difficulty::Hard => 3,
+        }
+    }
+
+    /// Percent chance per frame to jump while a bullet flies at the bot.
+    fn dodge_chance(self) -> u32 {
+        match self {
+            Difficulty::Easy => 0,
+            Difficulty::Normal => 5,
+            Difficulty::Hard => 25,
+        }
+    }
+}
+
+pub struct Bot {
+    /// `sim::PlayerState::id` of the player the bot controls.
+    id: u32,
+    difficulty: Difficulty,
+    /// Decided inputs waiting for the bot's reaction time to pass.
+    pending: VecDeque<Input>,
+    rng: Rng,
+    /// Whether the last decision pressed jump. Jumps need the key released in between.
+    jumped: bool,
+}
+
+impl Bot {
+    pub fn new(id: u32, difficulty: Difficulty, seed: u64) -> Self {
+        Self {
+            id,
+            difficulty,
+            pending: VecDeque::new(),
+            rng: Rng::new(seed),
+            jumped: false,
+        }
+    }
+
+    /// The bot's input for the next frame of `sim`.
+    pub fn input(&mut self, sim: &Sim) -> Input {
+        let decision = self.decide(sim);
+        self.pending.push_back(decision);
+        if self.pending.len() > self.difficulty.reaction_frames() {
+            self.pending.pop_front().unwrap()
+        } else {
+            Input::empty()
+        }
+    }
+
+    /// Walks to the closest player and shoots once level with them. Pathing is greedy: the bot
+    /// jumps at walls and towards players above it, and walks off ledges to reach those below.
+    fn decide(&mut self, sim: &Sim) -> Input {
+        let state = &sim.state;
+        let map = sim.map();
+        let me = match state.players.iter().find(|player| player.id == self.id) {
+            Some(me) => me,
+            None => return Input::empty(),
+        };
+        let (x, y) = (me.actor.x, me.actor.y);
+        let on_ground = me.actor.collides_at(map, x, y + 1);
+        let mut input = Input::empty();
+        let mut jump = false;
+
+        let target = state
+            .players
+            .iter()
+            .filter(|player| player.id != self.id)
+            .min_by_key(|player| (player.actor.x - x).abs() + (player.actor.y - y).abs());
+        if let Some(target) = target {
+            let dx = target.actor.x - x;
+            let dy = target.actor.y - y;
+            let (toward, away) = if dx < 0 {
+                (Input::LEFT, Input::RIGHT)
+            } else {
+                (Input::RIGHT, Input::LEFT)
+            };
+            let facing_target = (dx < 0) != me.facing_right;
+            if dy.abs() <= self.difficulty.aim_tolerance() {
+                if dx.abs() < MIN_RANGE {
+                    input |= away;
+                } else if !facing_target || dx.abs() > SHOOTING_RANGE / 2 {
+                    input |= toward;
+                }
+                if facing_target && dx.abs() >= MIN_RANGE && dx.abs() <= SHOOTING_RANGE {
+                    input |= Input::SHOOT;
+                }
+            } else {
+                if dx.abs() > PLAYER_SIZE {
+                    input |= toward;
+                }
+                // Players in the air come back down, only climb towards those standing above.
+                let target_on_ground =
+                    target
+                        .actor
+                        .collides_at(map, target.actor.x, target.actor.y + 1);
+                if dy < -PLAYER_SIZE && on_ground && target_on_ground {
+                    jump = true;
+                }
+            }
+        }
+
+        let dir = if input.contains(Input::LEFT) {
+            -1
+        } else if input.contains(Input::RIGHT) {
+            1
+        } else {
+            0
+        };
+        if dir != 0 && me.actor.collides_at(map, x + dir * 2, y) {
+            jump = true;
+        }
+
+        let (center_x, center_y) = (x + PLAYER_SIZE / 2, y + PLAYER_SIZE / 2);
+        let threatened = state.bullets.iter().any(|bullet| {
+            let dx = center_x - bullet.x.floor();
+            bullet.owner != self.id
+                && (dx > 0) == (bullet.vx > Fixed::ZERO)
+                && dx.abs() < DODGE_DISTANCE
+                && (bullet.y.floor() - center_y).abs() < PLAYER_SIZE
+        });
+        if threatened && self.rng.chance(self.difficulty.dodge_chance()) {
+            jump = true;
+        }
+
+        if jump && !self.jumped {
+            input |= Input::JUMP;
+        }
+        self.jumped = input.contains(Input::JUMP);
+        input
+    }
+}
@@ -0,0 +1,323 @@
+//! Headless bots that fill lobbies for testing and practice. Each bot joins through the lobby
+//! server like the game does, readies up and plays every match of its lobby until stopped.
+
+use backroll_transport_udp::{UdpConnectionConfig, UdpManager};
+use bevy_tasks::TaskPool;
+use bot::{Bot, Difficulty};
+use netplay::{Seat, Session};
+use quad_net::quad_socket::client::QuadSocket;
+use shared::map::MapInfo;
+use sim::{Sim, TileMap};
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Seconds between heartbeats, well below the server's idle timeout.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_SIMULATION_LAG: Duration = Duration::from_millis(500);
+
+struct LobbyConnection {
+    socket: QuadSocket,
+    last_heartbeat: Instant,
+}
+
+impl LobbyConnection {
+    fn send(&mut self, msg: &shared::ClientMsg) {
+        self.socket.send_bin(msg);
+    }
+
+    fn keep_alive(&mut self) {
+        if self.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            self.send(&shared::ClientMsg::Heartbeat);
+            self.last_heartbeat = Instant::now();
+        }
+    }
+
+    /// The next message from the server, if one arrived. Errors end the bot.
+    fn try_recv(&mut self) -> Result<Option<shared::ServerMsg>, shared::Error> {
+        self.keep_alive();
+        match self.socket.try_recv() {
+            None => Ok(None),
+            Some(data) => match nanoserde::DeBin::deserialize_bin(&data) {
+                Ok(shared::ServerMsg::Error(err)) => Err(err),
+                Ok(msg) => Ok(Some(msg)),
+                Err(_) => Err(shared::Error::BadMessage),
+            },
+        }
+    }
+}
+
+/// Why a bot stopped.
+enum Stop {
+    /// The lobby server sent this error.
+    Server(shared::Error),
+    /// The session of a match broke, see `netplay::Session::update`.
+    Session(String),
+}
+
+impl From<shared::Error> for Stop {
+    fn from(err: shared::Error) -> Self {
+        Stop::Server(err)
+    }
+}
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Stop::Server(err) => write!(f, "the lobby server replied {:?}", err),
+            Stop::Session(err) => write!(f, "the match broke: {}", err),
+        }
+    }
+}
+
+/// How `play` left a match.
+enum Ending {
+    /// With the results the server sent once everyone reported.
+    Over(shared::MatchResults),
+    /// The server paused the match to hand it off to a new roster, and has all our inputs.
+    /// It sends the resumed match's `Start` next.
+    Paused,
+}
+
+/// Plays a match until it is over or reaches the frame the server pauses it at. Once over it
+/// reports the end and keeps the session going until the results are in.
+fn play(
+    lobby: &mut LobbyConnection,
+    difficulty: Difficulty,
+    local_addr: SocketAddr,
+    connection_manager: &UdpManager,
+    task_pool: &TaskPool,
+    map: TileMap,
+    start: shared::Start,
+) -> Result<Ending, Stop> {
+    let mut local_id = None;
+    let mut seats = vec![];
+    let mut spawns = vec![];
+    for (id, player) in start.players.iter().enumerate() {
+        let addr = SocketAddr::new(player.ip.parse().unwrap(), player.port);
+        seats.push(if addr == local_addr {
+            local_id = Some(id as u32);
+            Seat::Local
+        } else {
+            Seat::Remote(addr)
+        });
+        spawns.push(player.spawn);
+    }
+    let local_id = local_id.expect("The match should include us");
+    let mut sim = Sim::new(&start.settings, map, &spawns).map_err(|_| shared::Error::BadMessage)?;
+    if let Some(snapshot) = &start.resume {
+        sim.restore(snapshot)
+            .map_err(|_| shared::Error::BadMessage)?;
+    }
+    let mut session = Session::new(task_pool, seats, &sim, |_, addr| {
+        connection_manager.connect(UdpConnectionConfig::unbounded(addr))
+    });
+    let mut bot = Bot::new(local_id, difficulty, start.settings.seed ^ local_id as u64);
+    let timestep = Duration::from_secs(1) / start.settings.tick_rate;
+    let mut behind = Duration::from_secs(0);
+    let mut last_update = Instant::now();
+
+    let mut reported = false;
+
+    loop {
+        // Besides roster updates only a pause or the results are sent during a match.
+        while let Some(msg) = lobby.try_recv()? {
+            match msg {
+                shared::ServerMsg::Pause(frame) => session.stop_at(frame),
+                shared::ServerMsg::MatchResults(results) => return Ok(Ending::Over(results)),
+                _ => {}
+            }
+        }
+        let ended = session.ended().cloned();
+        // A pause that crossed our report changes nothing, the server ends the match.
+        let paused = ended.is_none() && session.stopped(&sim);
+        for inputs in session.take_recorded(ended.is_some() || paused) {
+            lobby.send(&shared::ClientMsg::Inputs(inputs));
+        }
+        if paused {
+            lobby.send(&shared::ClientMsg::Paused);
+            return Ok(Ending::Paused);
+        }
+        if let Some(report) = ended {
+            if !reported {
+                lobby.send(&shared::ClientMsg::MatchOver(report));
+                reported = true;
+            }
+        }
+
+        let now = Instant::now();
+        behind = (behind + (now - last_update)).min(MAX_SIMULATION_LAG);
+        last_update = now;
+        while behind >= timestep {
+            behind -= timestep;
+            session
+                .update(&mut sim, |_, sim| bot.input(sim))
+                .map_err(|err| Stop::Session(err.to_string()))?;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Connects one bot, has it join the lobby and play until the server sends an error or a match
+/// breaks.
+fn run(options: &Options, index: usize, map: &MapInfo) -> Stop {
+    let task_pool = TaskPool::new();
+    let mut lobby = LobbyConnection {
+        socket: QuadSocket::connect(options.server).unwrap(),
+        last_heartbeat: Instant::now(),
+    };
+    lobby.send(&shared::ClientMsg::Hello(shared::Hello {
+        version: shared::PROTOCOL_VERSION,
+        build: shared::build_hash(),
+        map: map.hash,
+    }));
+
+    let local_port = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.local_addr())
+        .unwrap()
+        .port();
+    let local_addr = SocketAddr::new(shared::local_ip_towards(options.server), local_port);
+    let connection_manager = UdpManager::bind(
+        task_pool.clone(),
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port),
+    )
+    .unwrap();
+
+    let name = format!("Bot {}", index + 1);
+    lobby.send(&shared::ClientMsg::Join(shared::Join {
+        ip: local_addr.ip().to_string(),
+        port: local_addr.port(),
+        local: 0,
+        lobby: shared::LobbyChoice::Named(options.lobby.clone()),
+        name: name.clone(),
+        color: (160, 160, 160),
+        password: options.password.clone(),
+        private: false,
+    }));
+    lobby.send(&shared::ClientMsg::Ready);
+
+    loop {
+        let msg = match lobby.try_recv() {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(err) => return Stop::Server(err),
+        };
+        if let shared::ServerMsg::Start(start) = msg {
+            println!("{} is playing a match", name);
+            match play(
+                &mut lobby,
+                options.difficulty,
+                local_addr,
+                &connection_manager,
+                &task_pool,
+                TileMap::from_info(map),
+                start,
+            ) {
+                Ok(Ending::Over(results)) => {
+                    println!("{} saw the match end: {:?}", name, results);
+                    // The server unreadies everyone after a match.
+                    lobby.send(&shared::ClientMsg::Ready);
+                }
+                Ok(Ending::Paused) => println!("{} waits for players joining or leaving", name),
+                Err(err) => return err,
+            }
+        }
+    }
+}
+
+struct Options {
+    server: SocketAddr,
+    lobby: String,
+    password: String,
+    count: usize,
+    difficulty: Difficulty,
+    /// Path of the Tiled map JSON, which has to be the one the server plays.
+    map: String,
+}
+
+impl Options {
+    /// Reads `[--server HOST:PORT] [--password PASSWORD] [--count N]
+    /// [--difficulty easy|normal|hard] [--map PATH] LOBBY` from the command line. The server
+    /// defaults to one on this machine, the map to the game's, and the bots create `LOBBY` if
+    /// it does not exist yet.
+    fn from_args() -> Self {
+        let mut server = None;
+        let mut lobby = None;
+        let mut options = Options {
+            server: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8090),
+            lobby: String::new(),
+            password: String::new(),
+            count: 1,
+            difficulty: Difficulty::Normal,
+            map: "client/assets/map.json".to_string(),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => {
+                    let addr = args.next().expect("--server needs a value");
+                    server = Some(
+                        addr.to_socket_addrs()
+                            .ok()
+                            .and_then(|mut addrs| addrs.next())
+                            .expect("--server should be an address like 192.168.1.2:8090"),
+                    );
+                }
+                "--password" => options.password = args.next().expect("--password needs a value"),
+                "--count" => {
+                    options.count = args
+                        .next()
+                        .and_then(|count| count.parse().ok())
+                        .expect("--count should be a number")
+                }
+                "--difficulty" => {
+                    options.difficulty = args
+                        .next()
+                        .and_then(|difficulty| Difficulty::parse(&difficulty))
+                        .expect("--difficulty should be easy, normal or hard")
+                }
+                "--map" => options.map = args.next().expect("--map needs a value"),
+                _ => lobby = Some(arg),
+            }
+        }
+        options.server = server.unwrap_or(options.server);
+        options.lobby = lobby.expect("Name the lobby the bots should join");
+        options
+    }
+}
+
+fn main() {
+    let options = Arc::new(Options::from_args());
+    let map_json = match std::fs::read_to_string(&options.map) {
+        Ok(map_json) => map_json,
+        Err(err) => {
+            eprintln!("Can not read the map {}: {}", options.map, err);
+            std::process::exit(1);
+        }
+    };
+    let name = shared::map::name_from_path(&options.map);
+    let map = match MapInfo::parse(&name, &map_json) {
+        Ok(map) => Arc::new(map),
+        Err(err) => {
+            eprintln!("Can not play on the map: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let bots: Vec<_> = (0..options.count)
+        .map(|index| {
+            let options = options.clone();
+            let map = map.clone();
+            std::thread::spawn(move || {
+                let err = run(&options, index, &map);
+                eprintln!("Bot {} stopped, {}", index + 1, err);
+            })
+        })
+        .collect();
+    for bot in bots {
+        let _ = bot.join();
+    }
+}
@@ -0,0 +1,76 @@
+//! What bots decide to press in a few fixed situations. Bots answer after their reaction time,
+//! so each test asks for many frames of input on an unchanging simulation.
+
+use bot::{Bot, Difficulty};
+use sim::{Input, Sim, TileMap};
+
+/// Player 0 is the bot.
+const BOT: u32 = 0;
+/// Well past every difficulty's reaction time.
+const FRAMES: usize = 30;
+/// Where a player stands on the floor of `map`.
+const FLOOR_Y: i32 = 136;
+
+/// A 40x19 room with a floor, and a wall two tiles high in the middle of it.
+fn map() -> TileMap {
+    let (width, height) = (40, 19);
+    let mut solid = vec![false; width * height];
+    for x in 0..width {
+        solid[(height - 1) * width + x] = true;
+    }
+    for y in 16..18 {
+        solid[y * width + 20] = true;
+    }
+    TileMap::new(solid, width as i32, 8)
+}
+
+fn sim(spawns: &[(i32, i32)]) -> Sim {
+    let settings = shared::MatchSettings {
+        seed: 1,
+        map: "test".to_string(),
+        map_hash: 0,
+        tick_rate: 60,
+        rules: shared::Rules::default(),
+        weapons: vec![shared::Weapon::pistol()],
+        score_limit: 0,
+    };
+    Sim::new(&settings, map(), spawns).unwrap()
+}
+
+/// The bot's inputs once it reacted to `sim`.
+fn decisions(sim: &Sim) -> Vec<Input> {
+    let mut bot = Bot::new(BOT, Difficulty::Hard, 1);
+    let inputs: Vec<Input> = (0..FRAMES).map(|_| bot.input(sim)).collect();
+    inputs[FRAMES / 2..].to_vec()
+}
+
+#[test]
+fn walks_toward_its_target() {
+    // Too far to shoot.
+    let inputs = decisions(&sim(&[(16, FLOOR_Y), (136, 40)]));
+    assert!(inputs.iter().all(|input| input.contains(Input::RIGHT)));
+    assert!(!inputs.iter().any(|input| input.contains(Input::LEFT)));
+
+    let inputs = decisions(&sim(&[(136, FLOOR_Y), (16, 40)]));
+    assert!(inputs.iter().all(|input| input.contains(Input::LEFT)));
+}
+
+#[test]
+fn shoots_when_level_with_its_target() {
+    let inputs = decisions(&sim(&[(16, FLOOR_Y), (116, FLOOR_Y)]));
+    assert!(inputs.iter().all(|input| input.contains(Input::SHOOT)));
+
+    // Out of range, or too far above to hit.
+    let inputs = decisions(&sim(&[(16, FLOOR_Y), (150, FLOOR_Y - 20)]));
+    assert!(!inputs.iter().any(|input| input.contains(Input::SHOOT)));
+}
+
+#[test]
+fn jumps_at_walls() {
+    // Right in front of the wall, which starts at x = 160.
+    let inputs = decisions(&sim(&[(152, FLOOR_Y), (296, FLOOR_Y)]));
+    assert!(inputs.iter().any(|input| input.contains(Input::JUMP)));
+
+    let inputs = decisions(&sim(&[(16, FLOOR_Y), (136, FLOOR_Y)]));
+    assert!(!inputs.iter().any(|input| input.contains(Input::JUMP)));
+}
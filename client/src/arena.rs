@@ -0,0 +1,219 @@
+//! What players and spectators of a match both see: the map, the simulation and how to draw
+//! them. Where the inputs come from is up to the owner.
+
+use macroquad::prelude::*;
+
+use macroquad_particles::EmittersCache;
+use macroquad_tiled as tiled;
+
+use macroquad::telemetry;
+use sim::{Fixed, Sim, TileMap};
+
+use crate::consts;
+
+pub const EXPLOSION_FX: &'static str = r#"{"local_coords":false,"emission_shape":{"Point":[]},"one_shot":true,"lifetime":0.15,"lifetime_randomness":0,"explosiveness":0.65,"amount":41,"shape":{"Circle":{"subdivisions":10}},"emitting":false,"initial_direction":{"x":0,"y":-1},"initial_direction_spread":6.2831855,"initial_velocity":30,"initial_velocity_randomness":0.2,"linear_accel":0,"size":1.5000002,"size_randomness":0.4,"blend_mode":{"Alpha":[]},"colors_curve":{"start":{"r":0.8200004,"g":1,"b":0.31818175,"a":1},"mid":{"r":0.71000004,"g":0.36210018,"b":0,"a":1},"end":{"r":0.02,"g":0,"b":0.000000007152557,"a":1}},"gravity":{"x":0,"y":0},"post_processing":{}}
+"#;
+
+/// Width and height of the map in pixels, which the default camera shows all of.
+pub const MAP_WIDTH: f32 = 320.0;
+pub const MAP_HEIGHT: f32 = 152.0;
+
+/// Presentation data of a match player, indexed by `sim::PlayerState::id`.
+pub struct Player {
+    pub name: String,
+    pub color: Color,
+}
+
+pub struct Arena {
+    explosions: EmittersCache,
+    pub camera: Camera2D,
+    tiled_map: tiled::Map,
+    /// In `shared::Start` order, unlike `sim.state.players` they stay after a player dies.
+    pub players: Vec<Player>,
+    pub settings: shared::MatchSettings,
+    pub sim: Sim,
+}
+
+impl Arena {
+    /// Resumes from `start.resume` if the match is already under way. `map_json` is the map our
+    /// `Hello` told the lobby server about, which refused us if its own differs, so a match we
+    /// can not play means the server is broken.
+    pub async fn new(start: &shared::Start, map_json: &str) -> Result<Self, shared::Error> {
+        let settings = start.settings.clone();
+        if shared::content_hash(map_json.as_bytes()) != settings.map_hash {
+            error!("The match is on a different map than the lobby server has");
+            return Err(shared::Error::MapMismatch);
+        }
+
+        let explosions =
+            EmittersCache::new(nanoserde::DeJson::deserialize_json(EXPLOSION_FX).unwrap());
+
+        let tileset = load_texture("client/assets/tileset.png").await.unwrap();
+        tileset.set_filter(FilterMode::Nearest);
+
+        let tiled_map = tiled::load_map(map_json, &[("tileset.png", tileset)], &[]).unwrap();
+
+        // Collide with the map exactly like the lobby server and the bots do.
+        let map = match shared::map::MapInfo::parse(&settings.map, map_json) {
+            Ok(map) => map,
+            Err(err) => {
+                error!("Can not play on our map: {}", err);
+                return Err(shared::Error::BadMessage);
+            }
+        };
+
+        let camera = Camera2D::from_display_rect(Rect::new(0.0, 0.0, MAP_WIDTH, MAP_HEIGHT));
+
+        let players = start
+            .players
+            .iter()
+            .map(|player| {
+                let (r, g, b) = player.color;
+                Player {
+                    name: player.name.clone(),
+                    color: Color::from_rgba(r, g, b, 255),
+                }
+            })
+            .collect();
+        let spawns: Vec<_> = start.players.iter().map(|player| player.spawn).collect();
+        let mut sim = match Sim::new(&settings, TileMap::from_info(&map), &spawns) {
+            Ok(sim) => sim,
+            Err(err) => {
+                error!("Can not play the match: {}", err);
+                return Err(shared::Error::BadMessage);
+            }
+        };
+        if let Some(snapshot) = &start.resume {
+            if let Err(err) = sim.restore(snapshot) {
+                error!("Can not resume the match: {}", err);
+                return Err(shared::Error::BadMessage);
+            }
+        }
+
+        Ok(Self {
+            explosions,
+            camera,
+            tiled_map,
+            players,
+            settings,
+            sim,
+        })
+    }
+
+    pub fn timestep(&self) -> f32 {
+        1.0 / self.settings.tick_rate as f32
+    }
+
+    /// Shows where bullets hit, as returned by `Sim::advance`.
+    pub fn explode(&mut self, hits: Vec<(Fixed, Fixed)>) {
+        for (x, y) in hits {
+            self.explosions.spawn(vec2(x.to_f32(), y.to_f32()));
+        }
+    }
+
+    /// `local` has the ids of the players watching the screen. Their names and a HUD are only
+    /// drawn when there are several of them.
+    pub fn draw(&mut self, local: &[u32]) {
+        telemetry::begin_zone("draw world");
+        clear_background(BLACK);
+
+        set_camera(&self.camera);
+
+        for _ in 0..1 {
+            self.tiled_map.draw_tiles(
+                "main layer",
+                Rect::new(0.0, 0.0, MAP_WIDTH, MAP_HEIGHT),
+                None,
+            );
+        }
+
+        for player in &self.sim.state.players {
+            let info = &self.players[player.id as usize];
+            let pos = vec2(player.actor.x as f32, player.actor.y as f32);
+
+            // Players sharing the screen need their names to tell themselves apart.
+            if !local.contains(&player.id) || local.len() > 1 {
+                draw_text_ex(
+                    &info.name,
+                    pos.x - 4.0,
+                    pos.y - 6.0,
+                    TextParams {
+                        font_size: 30,
+                        font_scale: 0.15,
+                        color: info.color,
+                        ..Default::default()
+                    },
+                );
+            }
+
+            draw_rectangle(pos.x - 4.0, pos.y - 5.0, 16.0, 2.0, RED);
+            draw_rectangle(
+                pos.x - 4.0,
+                pos.y - 5.0,
+                player.health.clamp(0, self.settings.rules.max_health) as f32
+                    / self.settings.rules.max_health as f32
+                    * 16.0,
+                2.0,
+                GREEN,
+            );
+
+            if player.facing_right {
+                self.tiled_map.spr(
+                    "tileset",
+                    consts::PLAYER_SPRITE,
+                    Rect::new(pos.x, pos.y, 8.0, 8.0),
+                );
+            } else {
+                self.tiled_map.spr(
+                    "tileset",
+                    consts::PLAYER_SPRITE,
+                    Rect::new(pos.x + 8.0, pos.y, -8.0, 8.0),
+                );
+            }
+        }
+
+        telemetry::end_zone();
+
+        for bullet in &self.sim.state.bullets {
+            draw_circle(
+                bullet.x.to_f32(),
+                bullet.y.to_f32(),
+                1.0,
+                Color::new(1.0, 1.0, 0.8, 1.0),
+            );
+        }
+        {
+            let _z = telemetry::ZoneGuard::new("draw particles");
+            self.explosions.draw();
+        }
+
+        set_default_camera();
+
+        if local.len() > 1 {
+            self.draw_hud(local);
+        }
+    }
+
+    /// Lists the health and kills of the given players.
+    fn draw_hud(&self, ids: &[u32]) {
+        for (i, id) in ids.iter().enumerate() {
+            let info = &self.players[*id as usize];
+            let text = match self
+                .sim
+                .state
+                .players
+                .iter()
+                .find(|player| player.id == *id)
+            {
+                Some(player) => format!(
+                    "{}: {} health, {} kills",
+                    info.name,
+                    player.health.max(0),
+                    player.kills
+                ),
+                None => format!("{}: out", info.name),
+            };
+            draw_text(&text, 10.0, 20.0 + i as f32 * 20.0, 20.0, info.color);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use macroquad::prelude::*;
+use macroquad::ui::{hash, root_ui};
+
+use crate::lobby::LobbyClient;
+
+pub enum Choice {
+    Join(shared::LobbyChoice),
+    /// A match of the lobby we were in started anyway.
+    Started(shared::Start),
+}
+
+/// Lists the server's lobbies until the player joins one or creates a new one. `password` is
+/// sent along with the join, and `private` applies to new lobbies.
+pub async fn choose_lobby(
+    lobby: &mut LobbyClient,
+    password: &mut String,
+    private: &mut bool,
+) -> Choice {
+    lobby.refresh_lobbies();
+    loop {
+        if let Some(start) = lobby.update() {
+            return Choice::Started(start);
+        }
+
+        clear_background(BLACK);
+        if !lobby.draw_error() {
+            draw_text("Lobbies", 20.0, 40.0, 30.0, WHITE);
+            if root_ui().button(vec2(20.0, 60.0), "Refresh") {
+                lobby.refresh_lobbies();
+            }
+            if root_ui().button(vec2(100.0, 60.0), "New lobby") {
+                return Choice::Join(shared::LobbyChoice::New);
+            }
+            root_ui().input_text(hash!(), "Password", password);
+            root_ui().checkbox(hash!(), "Private", private);
+            if let Some(reason) = lobby.join_error() {
+                draw_text(reason, 20.0, 100.0, 30.0, RED);
+            }
+            if lobby.lobbies().is_empty() {
+                draw_text("No lobbies yet", 40.0, 140.0, 30.0, GRAY);
+            }
+            for (i, info) in lobby.lobbies().iter().enumerate() {
+                let y = 140.0 + i as f32 * 30.0;
+                let joinable = !info.in_progress && info.players < info.max_players;
+                draw_text(
+                    &format!(
+                        "{} {}/{} players, map {}{}{}",
+                        info.name,
+                        info.players,
+                        info.max_players,
+                        info.map,
+                        if info.in_progress {
+                            ", in progress"
+                        } else {
+                            ""
+                        },
+                        if info.password { ", password" } else { "" }
+                    ),
+                    40.0,
+                    y,
+                    30.0,
+                    if joinable { WHITE } else { GRAY },
+                );
+                if joinable
+                    && root_ui().button(vec2(500.0, y - 20.0), &*format!("Join {}", info.name))
+                {
+                    return Choice::Join(shared::LobbyChoice::Named(info.name.clone()));
+                }
+            }
+        }
+        next_frame().await;
+    }
+}
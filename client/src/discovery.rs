@@ -0,0 +1,93 @@
+use macroquad::prelude::*;
+
+use nanoserde::{DeBin, SerBin};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+/// Seconds between probes.
+const PROBE_INTERVAL: f64 = 1.0;
+/// Servers that stop answering for this long are dropped from the list.
+const FORGET_AFTER: f64 = 5.0;
+
+pub struct FoundServer {
+    /// The lobby server's TCP address.
+    pub addr: SocketAddr,
+    pub announcement: shared::Announcement,
+    last_seen: f64,
+}
+
+/// Finds lobby servers on the local network by broadcasting `shared::Discovery::Probe`.
+pub struct LanBrowser {
+    socket: Option<UdpSocket>,
+    servers: Vec<FoundServer>,
+    last_probe: Option<f64>,
+}
+
+impl LanBrowser {
+    pub fn new() -> Self {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+            socket.set_broadcast(true)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        });
+        let socket = match socket {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                warn!("LAN discovery is unavailable: {}", err);
+                None
+            }
+        };
+        Self {
+            socket,
+            servers: vec![],
+            last_probe: None,
+        }
+    }
+
+    pub fn servers(&self) -> &[FoundServer] {
+        &self.servers
+    }
+
+    /// Sends a probe every `PROBE_INTERVAL` and collects the answers.
+    pub fn update(&mut self) {
+        let socket = match &self.socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        let now = get_time();
+        if !matches!(self.last_probe, Some(last_probe) if now - last_probe < PROBE_INTERVAL) {
+            let probe = SerBin::serialize_bin(&shared::Discovery::Probe);
+            // Broadcasts do not reach servers on this machine everywhere, so ask loopback too.
+            for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST].iter() {
+                if let Err(err) = socket.send_to(&probe, (*ip, shared::DISCOVERY_PORT)) {
+                    debug!("Failed to send a discovery probe to {}: {}", ip, err);
+                }
+            }
+            self.last_probe = Some(now);
+        }
+
+        let mut buf = [0; 1024];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            let announcement = match DeBin::deserialize_bin(&buf[..len]) {
+                Ok(shared::Discovery::Announce(announcement)) => announcement,
+                _ => continue,
+            };
+            let addr = SocketAddr::new(from.ip(), announcement.port);
+            match self.servers.iter_mut().find(|server| server.addr == addr) {
+                Some(server) => {
+                    server.announcement = announcement;
+                    server.last_seen = now;
+                }
+                None => {
+                    info!("Found lobby server {} at {}", announcement.name, addr);
+                    self.servers.push(FoundServer {
+                        addr,
+                        announcement,
+                        last_seen: now,
+                    });
+                }
+            }
+        }
+        self.servers
+            .retain(|server| now - server.last_seen < FORGET_AFTER);
+    }
+}
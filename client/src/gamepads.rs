@@ -0,0 +1,73 @@
+//! Gamepads, which local players can play with instead of their keys: the first local player
+//! with the first gamepad connected, and so on. Gamepads are only read on desktop.
+
+use macroquad::prelude::*;
+use sim::Input;
+
+/// How far a stick has to be pushed to run.
+#[cfg(not(target_arch = "wasm32"))]
+const STICK_THRESHOLD: f32 = 0.5;
+
+pub struct Gamepads {
+    #[cfg(not(target_arch = "wasm32"))]
+    gilrs: Option<gilrs::Gilrs>,
+}
+
+impl Gamepads {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            gilrs: match gilrs::Gilrs::new() {
+                Ok(gilrs) => Some(gilrs),
+                Err(err) => {
+                    warn!("Gamepads are not available: {}", err);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Takes the events since the last call, which is what updates the state `input` reads.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn update(&mut self) {
+        if let Some(gilrs) = &mut self.gilrs {
+            while gilrs.next_event().is_some() {}
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn update(&mut self) {}
+
+    /// What the gamepad connected `index`th presses. Nothing if there is no such gamepad.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn input(&self, index: usize) -> Input {
+        use gilrs::{Axis, Button};
+
+        let gamepad = match &self.gilrs {
+            Some(gilrs) => match gilrs.gamepads().nth(index) {
+                Some((_, gamepad)) => gamepad,
+                None => return Input::empty(),
+            },
+            None => return Input::empty(),
+        };
+        let stick = gamepad.value(Axis::LeftStickX);
+        let mut input = Input::empty();
+        if gamepad.is_pressed(Button::DPadLeft) || stick < -STICK_THRESHOLD {
+            input |= Input::LEFT;
+        } else if gamepad.is_pressed(Button::DPadRight) || stick > STICK_THRESHOLD {
+            input |= Input::RIGHT;
+        }
+        if gamepad.is_pressed(Button::South) {
+            input |= Input::JUMP;
+        }
+        if gamepad.is_pressed(Button::West) || gamepad.is_pressed(Button::RightTrigger) {
+            input |= Input::SHOOT;
+        }
+        input
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn input(&self, _index: usize) -> Input {
+        Input::empty()
+    }
+}
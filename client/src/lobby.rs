@@ -0,0 +1,378 @@
+use macroquad::prelude::*;
+
+use quad_net::quad_socket::client::QuadSocket;
+use std::net::SocketAddr;
+
+/// Seconds between heartbeats, well below the server's idle timeout.
+const HEARTBEAT_INTERVAL: f64 = 2.0;
+
+/// Connection to the lobby server. It stays open during matches, so that players return to
+/// the same lobby afterwards.
+pub struct LobbyClient {
+    socket: QuadSocket,
+    server_addr: SocketAddr,
+    map_hash: u64,
+    ready: bool,
+    code: Option<String>,
+    roster: Vec<shared::LobbyPlayer>,
+    countdown: Option<u8>,
+    error: Option<shared::Error>,
+    /// Why the last `Join` was refused or its match could not be played, if so. Unlike `error`
+    /// the connection stays usable.
+    join_error: Option<String>,
+    last_heartbeat: f64,
+    lobbies: Vec<shared::LobbyInfo>,
+    results: Option<shared::MatchResults>,
+    /// Relayed inputs of the match we spectate, not yet taken by `take_inputs`.
+    inputs: Vec<shared::Inputs>,
+    /// The frame the server pauses our match at for a roster change, until `take_pause`.
+    pause: Option<u32>,
+    /// Our last `Join`, for the guests to follow us with.
+    last_join: Option<shared::Join>,
+    guests: Vec<Guest>,
+}
+
+/// Another player at our machine, see `LobbyClient::add_guest`.
+struct Guest {
+    lobby: LobbyClient,
+    /// How the guest looks.
+    name: String,
+    color: (u8, u8, u8),
+    /// The lobby the guest followed us into last.
+    code: Option<String>,
+}
+
+impl LobbyClient {
+    pub fn connect(server_addr: SocketAddr, map_hash: u64) -> Self {
+        info!("Connecting to lobby...");
+        let mut socket = QuadSocket::connect(server_addr).unwrap();
+        socket.send_bin(&shared::ClientMsg::Hello(shared::Hello {
+            version: shared::PROTOCOL_VERSION,
+            build: shared::build_hash(),
+            map: map_hash,
+        }));
+        Self {
+            socket,
+            server_addr,
+            map_hash,
+            ready: false,
+            code: None,
+            roster: vec![],
+            countdown: None,
+            error: None,
+            join_error: None,
+            last_heartbeat: get_time(),
+            lobbies: vec![],
+            results: None,
+            inputs: vec![],
+            pause: None,
+            last_join: None,
+            guests: vec![],
+        }
+    }
+
+    pub fn join(&mut self, join: shared::Join) {
+        self.join_error = None;
+        self.last_join = Some(join.clone());
+        self.socket.send_bin(&shared::ClientMsg::Join(join));
+    }
+
+    /// Brings another player at our machine along. The server seats one player per
+    /// connection, so the guest joins on a connection of their own, but plays our matches from
+    /// our address and in our session, as `shared::Join::local` 1 for the first guest and so
+    /// on. The guest follows us into every lobby we join and is as ready as we are.
+    pub fn add_guest(&mut self, name: String, color: (u8, u8, u8)) {
+        self.guests.push(Guest {
+            lobby: LobbyClient::connect(self.server_addr, self.map_hash),
+            name,
+            color,
+            code: None,
+        });
+    }
+
+    /// Watches the matches of a lobby instead of joining it. `update` returns their rosters as
+    /// they start, and `take_inputs` the players' inputs.
+    pub fn spectate(&mut self, lobby: String, password: String) {
+        self.socket
+            .send_bin(&shared::ClientMsg::Spectate(shared::Spectate {
+                lobby,
+                password,
+            }));
+    }
+
+    /// Sends the inputs of the player at our machine with this `shared::Join::local`, on their
+    /// own connection, for spectators and handoffs.
+    pub fn send_inputs(&mut self, local: u8, inputs: shared::Inputs) {
+        let lobby = match (local as usize).checked_sub(1) {
+            None => self,
+            Some(guest) => match self.guests.get_mut(guest) {
+                Some(guest) => &mut guest.lobby,
+                None => return,
+            },
+        };
+        lobby.socket.send_bin(&shared::ClientMsg::Inputs(inputs));
+    }
+
+    pub fn take_inputs(&mut self) -> Vec<shared::Inputs> {
+        std::mem::take(&mut self.inputs)
+    }
+
+    /// The frame the server asked us to stop our match at, because players joined or left.
+    /// Once we played up to it, flushed our inputs and sent `send_paused`, `update` returns the
+    /// resumed match.
+    pub fn take_pause(&mut self) -> Option<u32> {
+        self.pause.take()
+    }
+
+    /// Tells the server that our session reached the pause, for us and our guests.
+    pub fn send_paused(&mut self) {
+        self.socket.send_bin(&shared::ClientMsg::Paused);
+        for guest in &mut self.guests {
+            guest.lobby.send_paused();
+        }
+    }
+
+    /// Asks the server for its lobbies, which `lobbies` returns once they arrive.
+    pub fn refresh_lobbies(&mut self) {
+        self.socket.send_bin(&shared::ClientMsg::ListLobbies);
+    }
+
+    pub fn lobbies(&self) -> &[shared::LobbyInfo] {
+        &self.lobbies
+    }
+
+    pub fn join_error(&self) -> Option<&str> {
+        self.join_error.as_deref()
+    }
+
+    pub fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+        self.socket.send_bin(&if ready {
+            shared::ClientMsg::Ready
+        } else {
+            shared::ClientMsg::Unready
+        });
+    }
+
+    /// Leaves the lobby, so that another one can be joined on the same connection.
+    pub fn leave(&mut self) {
+        self.socket.send_bin(&shared::ClientMsg::Leave);
+        self.ready = false;
+        self.code = None;
+        self.roster.clear();
+        self.countdown = None;
+    }
+
+    /// Gives up on a lobby server that sent a match we can not play. `Hello` already checked
+    /// that we have its map, so only a broken server or peer does that. `reason` is shown
+    /// along with the lobbies to pick another one from.
+    pub fn fail(&mut self, reason: String) {
+        self.leave();
+        self.join_error = Some(reason);
+    }
+
+    /// Tells the server our match is over, for us and our guests. `results` returns the
+    /// outcome once every player reported.
+    pub fn report(&mut self, report: shared::MatchReport) {
+        self.results = None;
+        for guest in &mut self.guests {
+            guest.lobby.report(report.clone());
+        }
+        self.socket.send_bin(&shared::ClientMsg::MatchOver(report));
+    }
+
+    pub fn results(&self) -> Option<&shared::MatchResults> {
+        self.results.as_ref()
+    }
+
+    /// R toggles readiness, Enter asks to start as the host.
+    pub fn handle_keys(&mut self) {
+        if self.error.is_some() || self.code.is_none() {
+            return;
+        }
+        if is_key_pressed(KeyCode::R) {
+            self.set_ready(!self.ready);
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            self.socket.send_bin(&shared::ClientMsg::StartRequest);
+        }
+    }
+
+    /// Handles server messages and keeps the connection alive. Returns the match roster once
+    /// the server starts a match.
+    pub fn update(&mut self) -> Option<shared::Start> {
+        self.update_guests();
+        if self.error.is_none() {
+            if get_time() - self.last_heartbeat >= HEARTBEAT_INTERVAL {
+                self.socket.send_bin(&shared::ClientMsg::Heartbeat);
+                self.last_heartbeat = get_time();
+            }
+        }
+
+        while let Some(data) = self.socket.try_recv() {
+            let msg = match nanoserde::DeBin::deserialize_bin(&data) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    warn!("Ignoring malformed message from the lobby server");
+                    continue;
+                }
+            };
+            match msg {
+                shared::ServerMsg::Welcome => {
+                    info!("Lobby server accepted our handshake");
+                }
+                shared::ServerMsg::Error(err @ shared::Error::LobbyFull)
+                | shared::ServerMsg::Error(err @ shared::Error::WrongPassword)
+                | shared::ServerMsg::Error(err @ shared::Error::MatchInProgress) => {
+                    warn!("Could not join the lobby: {:?}", err);
+                    self.join_error = Some(error_message(&err));
+                }
+                shared::ServerMsg::Error(err) => {
+                    error!("Lobby server replied with an error: {:?}", err);
+                    self.error = Some(err);
+                }
+                shared::ServerMsg::Joined(shared::Joined { code }) => {
+                    info!("Joined lobby {}", code);
+                    // Errors that did not end the connection are over once we are in a lobby.
+                    self.error = None;
+                    self.code = Some(code);
+                }
+                shared::ServerMsg::LobbyUpdate(shared::LobbyUpdate { players }) => {
+                    self.roster = players;
+                }
+                shared::ServerMsg::Countdown(seconds_left) => {
+                    info!("Starting in {}...", seconds_left);
+                    self.countdown = Some(seconds_left);
+                }
+                shared::ServerMsg::CountdownCancelled => {
+                    info!("Countdown cancelled");
+                    self.countdown = None;
+                }
+                shared::ServerMsg::Start(start) => {
+                    info!("Starting...");
+                    self.countdown = None;
+                    self.results = None;
+                    self.pause = None;
+                    return Some(start);
+                }
+                shared::ServerMsg::LobbyList(lobbies) => {
+                    self.lobbies = lobbies;
+                }
+                shared::ServerMsg::MatchResults(results) => {
+                    info!("Match results: {:?}", results);
+                    // The server unreadies everyone after a match.
+                    self.ready = false;
+                    self.results = Some(results);
+                }
+                shared::ServerMsg::Inputs(inputs) => {
+                    self.inputs.push(inputs);
+                }
+                shared::ServerMsg::Pause(frame) => {
+                    info!("Pausing at frame {} for players joining or leaving", frame);
+                    self.pause = Some(frame);
+                }
+            }
+        }
+        None
+    }
+
+    /// Keeps the guests in our lobby and as ready as we are. Their matches are played from our
+    /// own `Start`, so they ignore theirs.
+    fn update_guests(&mut self) {
+        for (i, guest) in self.guests.iter_mut().enumerate() {
+            let _ = guest.lobby.update();
+            if guest.code != self.code {
+                match (&self.code, &self.last_join) {
+                    (Some(code), Some(join)) => guest.lobby.join(shared::Join {
+                        local: i as u8 + 1,
+                        lobby: shared::LobbyChoice::Named(code.clone()),
+                        name: guest.name.clone(),
+                        color: guest.color,
+                        private: false,
+                        ..join.clone()
+                    }),
+                    _ => guest.lobby.leave(),
+                }
+                guest.code = self.code.clone();
+            }
+            if guest.lobby.code.is_some() && guest.lobby.ready != self.ready {
+                guest.lobby.set_ready(self.ready);
+            }
+        }
+    }
+
+    pub fn draw(&self) {
+        clear_background(BLACK);
+        if self.draw_error() {
+            return;
+        }
+
+        let status = match (&self.code, self.countdown) {
+            (None, _) => "Connecting to lobby...".to_string(),
+            (Some(code), None) => format!("Lobby {}", code),
+            (Some(code), Some(seconds_left)) => {
+                format!("Lobby {}: starting in {}", code, seconds_left)
+            }
+        };
+        draw_text(&status, 20.0, 40.0, 30.0, WHITE);
+        draw_text(
+            if self.ready {
+                "Ready! Press R to unready"
+            } else {
+                "Press R when ready, Enter to start as host"
+            },
+            20.0,
+            80.0,
+            30.0,
+            WHITE,
+        );
+        for (i, player) in self.roster.iter().enumerate() {
+            let shared::LobbyPlayer {
+                name,
+                color: (r, g, b),
+                ready,
+                host,
+            } = player;
+            draw_text(
+                &format!(
+                    "{} {}{}",
+                    if *ready { "[ready]" } else { "[     ]" },
+                    name,
+                    if *host { " (host)" } else { "" }
+                ),
+                40.0,
+                140.0 + i as f32 * 30.0,
+                30.0,
+                Color::from_rgba(*r, *g, *b, 255),
+            );
+        }
+    }
+
+    /// Draws the server's error, if any. Returns whether there was one.
+    pub fn draw_error(&self) -> bool {
+        if let Some(err) = &self.error {
+            draw_text(&error_message(err), 20.0, 40.0, 30.0, RED);
+            return true;
+        }
+        false
+    }
+}
+
+pub fn error_message(err: &shared::Error) -> String {
+    match err {
+        shared::Error::VersionMismatch(version) => format!(
+            "The lobby server speaks protocol version {}, we speak {}",
+            version,
+            shared::PROTOCOL_VERSION
+        ),
+        shared::Error::BuildMismatch => {
+            "The lobby server runs a different build of the game".to_string()
+        }
+        shared::Error::MapMismatch => "The lobby server has a different map".to_string(),
+        shared::Error::LobbyFull => "That lobby is full".to_string(),
+        shared::Error::WrongPassword => "Wrong password".to_string(),
+        shared::Error::MatchInProgress => "That lobby is playing a match".to_string(),
+        shared::Error::NoSuchLobby => "There is no such lobby".to_string(),
+        err => format!("Lobby server error: {:?}", err),
+    }
+}
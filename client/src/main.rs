@@ -1,571 +1,779 @@
 use macroquad::prelude::*;
 
-use macroquad_particles as particles;
 //use macroquad_profiler as profiler;
-use macroquad_tiled as tiled;
 
-use backroll::{
-    command::{Command, Commands},
-    BackrollError, Event, P2PSession, Player as BackrollPlayer,
-    PlayerHandle as BackrollPlayerHandle,
-};
+use arena::Arena;
+use backroll::{BackrollError, Event};
+use backroll_transport::Peer;
 use backroll_transport_udp::{UdpConnectionConfig, UdpManager};
 use bevy_tasks::TaskPool;
-use bytemuck::{Pod, Zeroable};
+use bot::Bot;
+use gamepads::Gamepads;
+use lobby::LobbyClient;
 use macroquad::telemetry;
-use macroquad_platformer::{Actor, World as CollisionWorld};
-use ordered_float::OrderedFloat;
-use particles::EmittersCache;
-use quad_net::quad_socket::client::QuadSocket;
-use std::net::{Ipv4Addr, SocketAddr};
+use netplay::Session;
+use results::AfterMatch;
+use sim::Input;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+
+mod arena;
+mod browser;
+mod discovery;
+mod gamepads;
+mod lobby;
+mod menu;
+mod results;
+mod spectate;
+
+const MAP_PATH: &str = "client/assets/map.json";
 
 mod consts {
     use super::{Input, KeyCode};
-    pub const TIMESTEP: f32 = 1.0 / 60.0;
     pub const MAX_SIMULATION_LAG_SECONDS: f32 = 0.5;
-    pub const MAX_FRAMES_PER_VSYNC: usize = (MAX_SIMULATION_LAG_SECONDS / TIMESTEP) as usize;
-    pub const GRAVITY: f32 = 900.0;
-    pub const JUMP_SPEED: f32 = 250.0;
-    pub const RUN_SPEED: f32 = 150.0;
+    /// How long "left the game" notices stay up.
+    pub const LEFT_NOTICE_SECONDS: f64 = 5.0;
     pub const PLAYER_SPRITE: u32 = 120;
-    pub const BULLET_SPEED: f32 = 300.0;
-    pub const BULLET_INTERVAL_TICKS: u32 = 10;
-    pub const INPUT_MAP: [(Input, KeyCode); 4] = [
-        (Input::SHOOT, KeyCode::A),
-        (Input::LEFT, KeyCode::Left),
-        (Input::RIGHT, KeyCode::Right),
-        (Input::JUMP, KeyCode::Space),
+    /// One per player sharing the keyboard, so at most this many local players.
+    pub const KEYMAPS: [[(Input, KeyCode); 4]; 4] = [
+        [
+            (Input::SHOOT, KeyCode::A),
+            (Input::LEFT, KeyCode::Left),
+            (Input::RIGHT, KeyCode::Right),
+            (Input::JUMP, KeyCode::Space),
+        ],
+        [
+            (Input::SHOOT, KeyCode::Q),
+            (Input::LEFT, KeyCode::F),
+            (Input::RIGHT, KeyCode::H),
+            (Input::JUMP, KeyCode::T),
+        ],
+        [
+            (Input::SHOOT, KeyCode::U),
+            (Input::LEFT, KeyCode::J),
+            (Input::RIGHT, KeyCode::L),
+            (Input::JUMP, KeyCode::I),
+        ],
+        [
+            (Input::SHOOT, KeyCode::Kp0),
+            (Input::LEFT, KeyCode::Kp4),
+            (Input::RIGHT, KeyCode::Kp6),
+            (Input::JUMP, KeyCode::Kp8),
+        ],
     ];
+    /// Given to local players past the first.
+    pub const LOCAL_COLORS: [(u8, u8, u8); 3] = [(80, 180, 255), (120, 220, 90), (230, 90, 200)];
 }
 
-#[macro_use]
-extern crate bitflags;
-
-struct Player {
-    backroll_player_handle: BackrollPlayerHandle,
-    collider: Actor,
-    speed: Vec2,
-    prev_jump_down: bool,
-    facing_right: bool,
-    health: i32,
-    gun_clock: u32,
+/// Who plays a match player.
+enum Seat {
+    /// Someone at this machine, with the keys of the `consts::KEYMAPS` entry of this index or
+    /// the gamepad connected in this order.
+    Human(usize),
+    Bot(bot::Difficulty),
+    /// Someone at the machine with this address.
+    Remote(SocketAddr),
 }
 
-struct Bullet {
-    pos: Vec2,
-    speed: Vec2,
-    lived: f32,
-    lifetime: f32,
-}
-
-bitflags! {
-    #[repr(C)]
-    #[derive(Zeroable, Pod)]
-    pub struct Input: u8 {
-        const SHOOT = 0b1;
-        const LEFT = 0b10;
-        const RIGHT = 0b100;
-        const JUMP = 0b1000;
-    }
-}
-
-impl Input {
-    pub fn current() -> Input {
-        let mut current_inputs = Input::empty();
-        for (input, key_code) in &consts::INPUT_MAP {
-            if is_key_down(*key_code) {
-                current_inputs.insert(*input);
-            }
+fn current_input(keymap: &[(Input, KeyCode)]) -> Input {
+    let mut current_inputs = Input::empty();
+    for (input, key_code) in keymap {
+        if is_key_down(*key_code) {
+            current_inputs.insert(*input);
         }
-        current_inputs
     }
+    current_inputs
 }
 
-#[derive(Clone, Hash)]
-struct PlayerState {
-    x: OrderedFloat<f32>,
-    y: OrderedFloat<f32>,
-    vx: OrderedFloat<f32>,
-    vy: OrderedFloat<f32>,
-    prev_jump_down: bool,
-    facing_right: bool,
-    health: i32,
-    gun_clock: u32,
-}
-
-#[derive(Clone, Hash)]
-struct BulletState {
-    x: OrderedFloat<f32>,
-    y: OrderedFloat<f32>,
-    vx: OrderedFloat<f32>,
-    vy: OrderedFloat<f32>,
-    lived: OrderedFloat<f32>,
-    lifetime: OrderedFloat<f32>,
-}
-
-#[derive(Clone, Hash)]
-struct State {
-    pub players: Vec<PlayerState>,
-    pub bullets: Vec<BulletState>,
+/// What the player with `Seat::Human(controls)` presses.
+fn human_input(gamepads: &Gamepads, controls: usize) -> Input {
+    current_input(&consts::KEYMAPS[controls]) | gamepads.input(controls)
 }
 
-struct BackrollConfig;
-
-impl backroll::Config for BackrollConfig {
-    type Input = Input;
-    type State = State;
-}
-
-pub const EXPLOSION_FX: &'static str = r#"{"local_coords":false,"emission_shape":{"Point":[]},"one_shot":true,"lifetime":0.15,"lifetime_randomness":0,"explosiveness":0.65,"amount":41,"shape":{"Circle":{"subdivisions":10}},"emitting":false,"initial_direction":{"x":0,"y":-1},"initial_direction_spread":6.2831855,"initial_velocity":30,"initial_velocity_randomness":0.2,"linear_accel":0,"size":1.5000002,"size_randomness":0.4,"blend_mode":{"Alpha":[]},"colors_curve":{"start":{"r":0.8200004,"g":1,"b":0.31818175,"a":1},"mid":{"r":0.71000004,"g":0.36210018,"b":0,"a":1},"end":{"r":0.02,"g":0,"b":0.000000007152557,"a":1}},"gravity":{"x":0,"y":0},"post_processing":{}}
-"#;
-
 struct Game {
-    _connection_manager: UdpManager,
-    session: P2PSession<BackrollConfig>,
-    local_player: BackrollPlayerHandle,
-    explosions: EmittersCache,
-    collision_world: CollisionWorld,
-    camera: Camera2D,
-    tiled_map: tiled::Map,
-    players: Vec<Player>,
-    bullets: Vec<Bullet>,
-    frames_to_stall: u8,
+    session: Session,
+    /// Ids of the players at this machine, with their `Seat::Human` index.
+    local_players: Vec<(u32, usize)>,
+    /// Local players that bots play.
+    bots: Vec<(u32, Bot)>,
+    gamepads: Gamepads,
+    arena: Arena,
+    /// Players who disconnected, with when, for the "left the game" notices.
+    left: Vec<(u32, f64)>,
 }
 
 impl Game {
-    async fn new() -> Self {
-        async fn connect(
-            server_addr: SocketAddr,
-            collision_world: &mut CollisionWorld,
-        ) -> (
-            P2PSession<BackrollConfig>,
-            BackrollPlayerHandle,
-            Vec<Player>,
-            UdpManager,
-        ) {
-            let task_pool = TaskPool::new();
-
-            let local_port = portpicker::pick_unused_port()
-                .expect("Ran out of available ports to make connections with");
-            let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), local_port);
-            info!("Local addr: {:?}", local_addr);
-            let connection_manager = UdpManager::bind(task_pool.clone(), local_addr).unwrap();
-
-            let mut builder = P2PSession::build();
-
-            info!("Connecting to lobby...");
-            let mut socket = QuadSocket::connect(server_addr).unwrap();
-            socket.send_bin(&shared::Join(local_port));
-
-            loop {
-                if let Some(data) = socket.try_recv() {
-                    let shared::Start(players_data) =
-                        nanoserde::DeBin::deserialize_bin(&data).unwrap();
-                    info!("Starting...");
-                    let mut local_player = None;
-                    let mut players = Vec::new();
-                    for (port, (x, y)) in players_data {
-                        let backroll_player_handle = if port == local_port {
-                            info!("Adding local player");
-                            let backroll_player_handle = builder.add_player(BackrollPlayer::Local);
-                            local_player = Some(backroll_player_handle);
-                            backroll_player_handle
-                        } else {
-                            let remote_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
-                            info!("Adding remote player with addr {:?}", remote_addr);
-                            let remote_peer = connection_manager
-                                .connect(UdpConnectionConfig::unbounded(remote_addr));
-                            let backroll_player_handle =
-                                builder.add_player(BackrollPlayer::Remote(remote_peer));
-                            backroll_player_handle
-                        };
-                        players.push(Player {
-                            backroll_player_handle,
-                            collider: collision_world.add_actor(vec2(x as f32, y as f32), 8, 8),
-                            speed: vec2(0., 0.),
-                            prev_jump_down: false,
-                            facing_right: true,
-                            health: 100,
-                            gun_clock: 0,
-                        })
+    /// `seats` has who plays each player of `start`, and `connect` connects to the other
+    /// machines as `netplay::Session::new` does. Fails if the lobby server sent a match we can
+    /// not play.
+    async fn new(
+        task_pool: &TaskPool,
+        start: shared::Start,
+        map_json: &str,
+        seats: Vec<Seat>,
+        connect: impl FnMut(u32, SocketAddr) -> Peer,
+    ) -> Result<Self, shared::Error> {
+        let arena = Arena::new(&start, map_json).await?;
+
+        let mut local_players = Vec::new();
+        let mut bots = Vec::new();
+        let mut session_seats = Vec::new();
+        for (id, seat) in seats.into_iter().enumerate() {
+            let id = id as u32;
+            session_seats.push(match seat {
+                Seat::Human(controls) => {
+                    if controls >= consts::KEYMAPS.len() {
+                        return Err(shared::Error::BadMessage);
                     }
-                    let session = builder.start(task_pool).unwrap();
-                    break (session, local_player.unwrap(), players, connection_manager);
+                    local_players.push((id, controls));
+                    netplay::Seat::Local
                 }
-                next_frame().await;
-            }
-        }
-        let explosions =
-            EmittersCache::new(nanoserde::DeJson::deserialize_json(EXPLOSION_FX).unwrap());
-
-        let tileset = load_texture("client/assets/tileset.png").await.unwrap();
-        tileset.set_filter(FilterMode::Nearest);
-
-        let tiled_map_json = load_string("client/assets/map.json").await.unwrap();
-        let tiled_map = tiled::load_map(&tiled_map_json, &[("tileset.png", tileset)], &[]).unwrap();
-
-        let mut static_colliders = vec![];
-        for (_x, _y, tile) in tiled_map.tiles("main layer", None) {
-            static_colliders.push(tile.is_some());
+                Seat::Bot(difficulty) => {
+                    let seed = arena.settings.seed ^ id as u64;
+                    bots.push((id, Bot::new(id, difficulty, seed)));
+                    netplay::Seat::Local
+                }
+                Seat::Remote(addr) => netplay::Seat::Remote(addr),
+            });
         }
+        let session = Session::new(task_pool, session_seats, &arena.sim, connect);
 
-        let mut collision_world = CollisionWorld::new();
-        collision_world.add_static_tiled_layer(static_colliders, 8., 8., 40, 1);
-
-        let camera = Camera2D::from_display_rect(Rect::new(0.0, 0.0, 320.0, 152.0));
-
-        let (session, local_player, players, connection_manager) =
-            connect("0.0.0.0:8090".parse().unwrap(), &mut collision_world).await;
-
-        Self {
-            _connection_manager: connection_manager,
+        Ok(Self {
             session,
-            local_player,
-            explosions,
-            tiled_map,
-            collision_world,
-            camera,
-            players,
-            bullets: vec![],
-            frames_to_stall: 0,
-        }
+            local_players,
+            bots,
+            gamepads: Gamepads::new(),
+            arena,
+            left: vec![],
+        })
     }
 
-    fn update(&mut self) {
+    /// Plays the next frame. Fails if the session broke, see `netplay::Session::update`.
+    fn update(&mut self) -> Result<(), BackrollError> {
         telemetry::begin_zone("Main loop");
 
-        telemetry::begin_zone("pre flush");
-        self.run_commands(self.session.poll());
-        telemetry::end_zone();
-
-        if self.frames_to_stall > 0 {
-            self.frames_to_stall -= 1;
-        } else if self.session.is_synchronized() {
-            telemetry::begin_zone("local input");
-            match self
-                .session
-                .add_local_input(self.local_player, Input::current())
-            {
-                Ok(()) => {
-                    telemetry::begin_zone("advance frame");
-                    self.run_commands(self.session.advance_frame());
-                    telemetry::end_zone();
-                }
-                Err(BackrollError::ReachedPredictionBarrier) => {
-                    warn!("Prediction barrier reached. Stalling.");
-                }
-                Err(err) => {
-                    panic!("Error in adding local input: ({:?}) {}", err, err);
-                }
+        self.gamepads.update();
+        let Self {
+            session,
+            local_players,
+            bots,
+            gamepads,
+            arena,
+            ..
+        } = self;
+        let updated = session.update(&mut arena.sim, |id, sim| {
+            match local_players.iter().find(|(local, _)| *local == id) {
+                Some((_, controls)) => human_input(gamepads, *controls),
+                None => bots
+                    .iter_mut()
+                    .find(|(bot_id, _)| *bot_id == id)
+                    .map_or(Input::empty(), |(_, bot)| bot.input(sim)),
             }
-            telemetry::end_zone();
-        }
+        });
+        let updated = updated.map(|hits| arena.explode(hits));
 
-        //profiler::profiler(profiler::ProfilerParams {
-        //    fps_counter_pos: vec2(50.0, 20.0),
-        //});
-
-        telemetry::end_zone();
-    }
-
-    fn run_commands(&mut self, commands: Commands<BackrollConfig>) {
-        for command in commands {
-            match command {
-                Command::Save(save_state) => {
-                    save_state.save(State {
-                        players: self
-                            .players
-                            .iter()
-                            .map(|player| {
-                                let pos = self.collision_world.actor_pos(player.collider);
-                                PlayerState {
-                                    x: OrderedFloat(pos.x),
-                                    y: OrderedFloat(pos.y),
-                                    vx: OrderedFloat(player.speed.x),
-                                    vy: OrderedFloat(player.speed.y),
-                                    prev_jump_down: player.prev_jump_down,
-                                    facing_right: player.facing_right,
-                                    health: player.health,
-                                    gun_clock: player.gun_clock,
-                                }
-                            })
-                            .collect(),
-                        bullets: self
-                            .bullets
-                            .iter()
-                            .map(|bullet| BulletState {
-                                x: OrderedFloat(bullet.pos.x),
-                                y: OrderedFloat(bullet.pos.y),
-                                vx: OrderedFloat(bullet.speed.x),
-                                vy: OrderedFloat(bullet.speed.y),
-                                lived: OrderedFloat(bullet.lived),
-                                lifetime: OrderedFloat(bullet.lifetime),
-                            })
-                            .collect(),
-                    });
-                }
-                Command::Load(load_state) => {
-                    let state = load_state.load();
-                    for (player_state, player) in state.players.iter().zip(self.players.iter_mut())
-                    {
-                        *player = Player {
-                            backroll_player_handle: player.backroll_player_handle,
-                            collider: player.collider,
-                            speed: Vec2::new(player_state.vx.0, player_state.vy.0),
-                            prev_jump_down: player_state.prev_jump_down,
-                            facing_right: player_state.facing_right,
-                            health: player_state.health,
-                            gun_clock: player_state.gun_clock,
-                        };
-                        self.collision_world.set_actor_position(
-                            player.collider,
-                            Vec2::new(player_state.x.0, player_state.y.0),
-                        );
-                    }
-                    for (bullet_state, bullet) in state.bullets.iter().zip(self.bullets.iter_mut())
-                    {
-                        *bullet = Bullet {
-                            pos: vec2(bullet_state.x.0, bullet_state.y.0),
-                            speed: vec2(bullet_state.vx.0, bullet_state.vy.0),
-                            lived: bullet_state.lived.0,
-                            lifetime: bullet_state.lifetime.0,
-                        }
-                    }
-                }
-                Command::AdvanceFrame(input) => {
-                    for player in &mut self.players {
-                        let pos = self.collision_world.actor_pos(player.collider);
-                        let on_ground = self
-                            .collision_world
-                            .collide_check(player.collider, pos + vec2(0., 1.));
-                        {
-                            let player_input = input
-                                .get(player.backroll_player_handle)
-                                .expect("Player should still be valid"); // TODO: Is player valid even after disconnect?
-
-                            if player_input.contains(Input::RIGHT) {
-                                player.speed.x = consts::RUN_SPEED;
-                            } else if player_input.contains(Input::LEFT) {
-                                player.speed.x = -consts::RUN_SPEED;
-                            } else {
-                                player.speed.x = 0.;
-                            }
-                            if player_input.contains(Input::JUMP) && !player.prev_jump_down {
-                                if on_ground {
-                                    player.speed.y = -consts::JUMP_SPEED;
-                                }
-                            }
-                            player.prev_jump_down = player_input.contains(Input::JUMP);
-                            if player_input.contains(Input::SHOOT) {
-                                if player.gun_clock == 0 {
-                                    let dir = if player.facing_right {
-                                        vec2(1.0, 0.0)
-                                    } else {
-                                        vec2(-1.0, 0.0)
-                                    };
-                                    self.bullets.push(Bullet {
-                                        pos: pos + vec2(4.0, 4.0) + dir * 8.0,
-                                        speed: dir * consts::BULLET_SPEED,
-                                        lived: 0.0,
-                                        lifetime: 0.7,
-                                    });
-                                }
-                                player.gun_clock += 1;
-                                player.gun_clock %= consts::BULLET_INTERVAL_TICKS;
-                            } else {
-                                player.gun_clock = 0;
-                            }
-                        }
-
-                        if player.speed.x < 0.0 {
-                            player.facing_right = false;
-                        }
-                        if player.speed.x > 0.0 {
-                            player.facing_right = true;
-                        }
-
-                        if on_ground == false {
-                            player.speed.y += consts::GRAVITY * consts::TIMESTEP;
-                        }
-
-                        self.collision_world
-                            .move_h(player.collider, player.speed.x * consts::TIMESTEP);
-                        if !self
-                            .collision_world
-                            .move_v(player.collider, player.speed.y * consts::TIMESTEP)
-                        {
-                            player.speed.y = 0.0;
-                        }
-
-                        // HACK: clear the position remainders that are not saved in the backroll state.
-                        self.collision_world.set_actor_position(
-                            player.collider,
-                            self.collision_world.actor_pos(player.collider),
-                        );
-                    }
-
-                    {
-                        let _z = telemetry::ZoneGuard::new("update bullets");
-
-                        for bullet in &mut self.bullets {
-                            bullet.pos += bullet.speed * consts::TIMESTEP;
-                            bullet.lived += consts::TIMESTEP;
-                        }
-                        let explosions = &mut self.explosions;
-                        let collision_world = &mut self.collision_world;
-                        let players = &mut self.players;
-
-                        self.bullets.retain(|bullet| {
-                            if collision_world.solid_at(bullet.pos) {
-                                explosions.spawn(bullet.pos);
-                                return false;
-                            }
-                            for player in players.iter_mut() {
-                                let player_pos = collision_world.actor_pos(player.collider);
-                                if Rect::new(player_pos.x, player_pos.y, 8.0, 8.0)
-                                    .contains(bullet.pos)
-                                {
-                                    player.health -= 5;
-                                    explosions.spawn(bullet.pos);
-                                    return false;
-                                }
-                            }
-                            bullet.lived < bullet.lifetime
-                        });
-                    }
-
-                    self.players.retain(|player| player.health > 0);
-                }
-                Command::Event(Event::Connected(player_handle)) => {
+        for event in self.session.take_events() {
+            match event {
+                Event::Connected(player_handle) => {
                     info!("Remote player connected: {:?}", player_handle);
                 }
-                Command::Event(Event::Synchronizing {
+                Event::Synchronizing {
                     player,
                     count,
                     total,
-                }) => {
+                } => {
                     debug!(
                         "Remote player sync: {:?}, count: {}, total: {}",
                         player, count, total
                     );
                 }
-                Command::Event(Event::Synchronized(player)) => {
+                Event::Synchronized(player) => {
                     info!("Remote player synced: {:?}", player);
                 }
-                Command::Event(Event::Running) => {
+                Event::Running => {
                     info!("P2PSession is all synchronized and is ready to run");
                 }
-                Command::Event(Event::Disconnected(player)) => {
+                Event::Disconnected(player) => {
                     info!("Remote player disconnected: {:?}", player);
+                    for id in self.session.player_ids(player) {
+                        self.left.push((id, get_time()));
+                    }
                 }
-                Command::Event(Event::TimeSync { frames_ahead }) => {
-                    debug!("Received stall request: {}", frames_ahead);
-                    self.frames_to_stall = frames_ahead;
-                }
-                Command::Event(Event::ConnectionInterrupted {
+                Event::ConnectionInterrupted {
                     player,
                     disconnect_timeout,
-                }) => {
+                } => {
                     info!(
                         "Remote player interrupted: {:?}, timeout: {:?}",
                         player, disconnect_timeout
                     );
                 }
-                Command::Event(Event::ConnectionResumed(player)) => {
+                Event::ConnectionResumed(player) => {
                     info!("Remote player resumed: {:?}", player);
                 }
+                Event::TimeSync { .. } => {}
             }
         }
+
+        //profiler::profiler(profiler::ProfilerParams {
+        //    fps_counter_pos: vec2(50.0, 20.0),
+        //});
+
+        telemetry::end_zone();
+        updated
     }
 
     fn draw(&mut self) {
-        telemetry::begin_zone("draw world");
-        clear_background(BLACK);
+        let local: Vec<u32> = self.local_players.iter().map(|(id, _)| *id).collect();
+        self.arena.draw(&local);
+
+        let now = get_time();
+        self.left
+            .retain(|(_, since)| now - since < consts::LEFT_NOTICE_SECONDS);
+        for (i, (id, _)) in self.left.iter().enumerate() {
+            let player = &self.arena.players[*id as usize];
+            draw_text(
+                &format!("{} left the game", player.name),
+                20.0,
+                screen_height() - 15.0 - i as f32 * 20.0,
+                20.0,
+                player.color,
+            );
+        }
+    }
+}
 
-        set_camera(&self.camera);
+/// Binds a UDP socket to play matches over. Returns the address other players reach it on,
+/// along with it.
+fn bind_udp(task_pool: &TaskPool, server_addr: SocketAddr) -> (SocketAddr, UdpManager) {
+    let local_port = portpicker::pick_unused_port()
+        .expect("Ran out of available ports to make connections with");
+    let local_addr = SocketAddr::new(shared::local_ip_towards(server_addr), local_port);
+    info!("Local addr: {:?}", local_addr);
+    let connection_manager = UdpManager::bind(
+        task_pool.clone(),
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port),
+    )
+    .unwrap();
+    (local_addr, connection_manager)
+}
 
-        for _ in 0..1 {
-            self.tiled_map
-                .draw_tiles("main layer", Rect::new(0.0, 0.0, 320.0, 152.0), None);
+/// The connection to the lobby server and the UDP socket matches are played over. Both last
+/// across matches.
+struct Connection {
+    lobby: LobbyClient,
+    local_addr: SocketAddr,
+    connection_manager: UdpManager,
+    task_pool: TaskPool,
+    /// The map our `Hello` told the lobby server about.
+    map_json: String,
+    /// Simulated network conditions for the links to other players.
+    netsim: Option<netsim::Conditions>,
+}
+
+impl Connection {
+    /// `local_players` share this machine, every one past the first as a guest.
+    fn new(
+        server_addr: SocketAddr,
+        map_json: String,
+        local_players: usize,
+        netsim: Option<netsim::Conditions>,
+    ) -> Self {
+        let task_pool = TaskPool::new();
+        let (local_addr, connection_manager) = bind_udp(&task_pool, server_addr);
+        let mut lobby =
+            LobbyClient::connect(server_addr, shared::content_hash(map_json.as_bytes()));
+        for i in 1..local_players {
+            lobby.add_guest(format!("Player {}", i + 1), consts::LOCAL_COLORS[i - 1]);
         }
 
-        for player in &self.players {
-            let pos = self.collision_world.actor_pos(player.collider);
-
-            if player.backroll_player_handle.0 != self.local_player.0 {
-                draw_text_ex(
-                    &format!("player {}", player.backroll_player_handle.0),
-                    pos.x - 4.0,
-                    pos.y - 6.0,
-                    TextParams {
-                        font_size: 30,
-                        font_scale: 0.15,
-                        ..Default::default()
-                    },
-                );
+        Self {
+            lobby,
+            local_addr,
+            connection_manager,
+            task_pool,
+            map_json,
+            netsim,
+        }
+    }
+
+    /// Joins the lobby named in `options`, or one the player picks, and waits there until a
+    /// match starts.
+    async fn join(&mut self, options: &mut Options) -> shared::Start {
+        loop {
+            let lobby_choice = match options.lobby.take() {
+                Some(lobby_choice) => lobby_choice,
+                None => match browser::choose_lobby(
+                    &mut self.lobby,
+                    &mut options.password,
+                    &mut options.private,
+                )
+                .await
+                {
+                    browser::Choice::Join(lobby_choice) => lobby_choice,
+                    browser::Choice::Started(start) => return start,
+                },
+            };
+            self.lobby.join(shared::Join {
+                ip: self.local_addr.ip().to_string(),
+                port: self.local_addr.port(),
+                local: 0,
+                lobby: lobby_choice,
+                name: options.name.clone(),
+                color: options.color,
+                password: options.password.clone(),
+                private: options.private,
+            });
+            if let Some(start) = self.wait_for_start().await {
+                return start;
             }
+        }
+    }
 
-            draw_rectangle(pos.x as f32 - 4.0, pos.y as f32 - 5.0, 16.0, 2.0, RED);
-            draw_rectangle(
-                pos.x as f32 - 4.0,
-                pos.y as f32 - 5.0,
-                player.health.clamp(0, 100) as f32 / 100.0 * 16.0,
-                2.0,
-                GREEN,
-            );
+    /// Plays the players of `start` at our address with the controls of their
+    /// `shared::Join::local`, ours first and then our guests'.
+    fn seats(&self, start: &shared::Start) -> Vec<Seat> {
+        start
+            .players
+            .iter()
+            .map(|player| {
+                let addr = SocketAddr::new(player.ip.parse().unwrap(), player.port);
+                if addr == self.local_addr {
+                    info!("Adding local player {}", player.local);
+                    Seat::Human(player.local as usize)
+                } else {
+                    info!("Adding remote player with addr {:?}", addr);
+                    Seat::Remote(addr)
+                }
+            })
+            .collect()
+    }
 
-            if player.facing_right {
-                self.tiled_map.spr(
-                    "tileset",
-                    consts::PLAYER_SPRITE,
-                    Rect::new(pos.x, pos.y, 8.0, 8.0),
-                );
-            } else {
-                self.tiled_map.spr(
-                    "tileset",
-                    consts::PLAYER_SPRITE,
-                    Rect::new(pos.x + 8.0, pos.y, -8.0, 8.0),
-                );
+    /// Connects to the machine at `addr` over our UDP socket, given the id of its first player
+    /// in the match with this seed.
+    fn connect(&self, seed: u64, id: u32, addr: SocketAddr) -> Peer {
+        let remote_peer = self
+            .connection_manager
+            .connect(UdpConnectionConfig::unbounded(addr));
+        match &self.netsim {
+            Some(conditions) => netsim::wrap(
+                &self.task_pool,
+                remote_peer,
+                conditions.clone(),
+                seed ^ id as u64,
+            ),
+            None => remote_peer,
+        }
+    }
+
+    /// Shows the lobby until the next match starts. Returns `None` if the server refused our
+    /// `Join`.
+    async fn wait_for_start(&mut self) -> Option<shared::Start> {
+        loop {
+            if let Some(start) = self.lobby.update() {
+                return Some(start);
+            }
+            if self.lobby.join_error().is_some() {
+                return None;
             }
+            self.lobby.handle_keys();
+            self.lobby.draw();
+            next_frame().await;
         }
+    }
+}
 
-        telemetry::end_zone();
+/// Plays a match to its end, resuming it whenever the server hands it off to a new roster.
+/// Returns our report of how it ended, once the results are in, or why we can not play the
+/// match.
+async fn play(
+    connection: &mut Connection,
+    mut start: shared::Start,
+) -> Result<shared::MatchReport, String> {
+    loop {
+        let seats = connection.seats(&start);
+        let seed = start.settings.seed;
+        let game = Game::new(
+            &connection.task_pool,
+            start,
+            &connection.map_json,
+            seats,
+            |id, addr| connection.connect(seed, id, addr),
+        )
+        .await
+        .map_err(|err| lobby::error_message(&err))?;
+        let ending = run(game, Some(&mut connection.lobby))
+            .await
+            .map_err(|err| format!("The match broke: {}", err))?;
+        match ending {
+            Ending::Over(report) => return Ok(report),
+            Ending::Paused => start = wait_for_resume(&mut connection.lobby).await,
+        }
+    }
+}
 
-        for bullet in &self.bullets {
-            draw_circle(
-                bullet.pos.x,
-                bullet.pos.y,
-                1.0,
-                Color::new(1.0, 1.0, 0.8, 1.0),
-            );
+/// Waits for the server to resume a paused match.
+async fn wait_for_resume(lobby: &mut LobbyClient) -> shared::Start {
+    loop {
+        if let Some(start) = lobby.update() {
+            return start;
         }
-        {
-            let _z = telemetry::ZoneGuard::new("draw particles");
-            self.explosions.draw();
+        clear_background(BLACK);
+        if !lobby.draw_error() {
+            draw_text("Players are joining or leaving...", 20.0, 40.0, 30.0, WHITE);
         }
+        next_frame().await;
+    }
+}
 
-        set_default_camera();
+/// Practice matches against bots, without a lobby server.
+async fn practice(options: &Options, map_json: &str) {
+    let map = match shared::map::MapInfo::parse(&shared::map::name_from_path(MAP_PATH), map_json) {
+        Ok(map) => map,
+        Err(err) => {
+            error!("Can not practice on our map: {}", err);
+            return;
+        }
+    };
+    let task_pool = TaskPool::new();
+    loop {
+        // `SystemTime` panics on wasm, miniquad asks the browser there.
+        let seed = (macroquad::miniquad::date::now() * 1e9) as u64;
+        let humans = options.local_players;
+        let count = humans + options.bots;
+        let name = if options.name.is_empty() {
+            "You".to_string()
+        } else {
+            options.name.clone()
+        };
+        let names: Vec<String> = std::iter::once(name)
+            .chain((2..=humans).map(|i| format!("Player {}", i)))
+            .chain((1..=options.bots).map(|i| format!("Bot {}", i)))
+            .collect();
+        let start = shared::Start {
+            players: names
+                .iter()
+                .zip(map.assign_spawns(count))
+                .enumerate()
+                .map(|(i, (name, (x, y)))| shared::StartPlayer {
+                    ip: Ipv4Addr::LOCALHOST.to_string(),
+                    port: 0,
+                    spawn: (x.floor() as i32, y.floor() as i32),
+                    local: i as u8,
+                    name: name.clone(),
+                    color: if i == 0 {
+                        options.color
+                    } else if i < humans {
+                        consts::LOCAL_COLORS[i - 1]
+                    } else {
+                        (160, 160, 160)
+                    },
+                })
+                .collect(),
+            settings: shared::MatchSettings {
+                seed,
+                map: map.name.clone(),
+                map_hash: map.hash,
+                tick_rate: 60,
+                rules: shared::Rules::default(),
+                weapons: vec![shared::Weapon::pistol()],
+                score_limit: 0,
+            },
+            resume: None,
+        };
+        let seats = (0..humans)
+            .map(Seat::Human)
+            .chain((0..options.bots).map(|_| Seat::Bot(options.difficulty)))
+            .collect();
+        let game = Game::new(&task_pool, start, map_json, seats, |_, _| {
+            unreachable!("Practice matches have no remote players")
+        })
+        .await
+        .expect("Our own map should load");
+        // Without a lobby server nothing pauses the match.
+        let winner = match run(game, None).await {
+            Ok(Ending::Over(report)) => report.winner,
+            Ok(Ending::Paused) => None,
+            Err(err) => {
+                error!("The practice match broke: {}", err);
+                return;
+            }
+        };
+        let winner = winner.map(|winner| names[winner as usize].as_str());
+        if !results::show_practice(winner).await {
+            return;
+        }
     }
 }
 
-#[macroquad::main("Platformer")]
-async fn main() {
-    let mut game = Game::new().await;
+/// How `run` left a match.
+enum Ending {
+    /// With our report, which the lobby server has along with everyone else's.
+    Over(shared::MatchReport),
+    /// The lobby server paused the match to hand it off to a new roster, and has all our
+    /// inputs.
+    Paused,
+}
+
+/// How far our session got in telling the lobby server about the match.
+#[derive(Default)]
+struct Progress {
+    paused: bool,
+    reported: bool,
+}
+
+/// Sends `lobby` the recorded inputs of `session`, and that it reached the frame the server
+/// pauses the match at or how the match ended.
+fn follow(lobby: &mut LobbyClient, game: &mut Game, progress: &mut Progress) {
+    let session = &mut game.session;
+    let sim = &game.arena.sim;
+    if let Some(frame) = lobby.take_pause() {
+        session.stop_at(frame);
+    }
+    let ended = session.ended().cloned();
+    // A pause that crossed our report changes nothing, the server ends the match.
+    let paused = ended.is_none() && session.stopped(sim);
+    for inputs in session.take_recorded(ended.is_some() || paused) {
+        let local = game
+            .local_players
+            .iter()
+            .find(|(id, _)| *id == inputs.player)
+            .map_or(0, |(_, controls)| *controls);
+        lobby.send_inputs(local as u8, inputs);
+    }
+    if paused && !progress.paused {
+        lobby.send_paused();
+        progress.paused = true;
+    }
+    if let Some(report) = ended {
+        if !progress.reported {
+            lobby.report(report);
+            progress.reported = true;
+        }
+    }
+}
+
+/// Runs the match until it is over or reaches the frame the lobby server pauses it at. Online
+/// it reports the end and keeps the session going until everyone else did too. Fails if the
+/// session broke.
+async fn run(mut game: Game, mut lobby: Option<&mut LobbyClient>) -> Result<Ending, BackrollError> {
+    let max_frames_per_vsync =
+        (consts::MAX_SIMULATION_LAG_SECONDS / game.arena.timestep()) as usize;
 
     let mut seconds_behind = 0.0;
+    let mut progress = Progress::default();
 
     loop {
+        let ended = game.session.ended().cloned();
+        match &mut lobby {
+            Some(lobby) => {
+                let _ = lobby.update();
+                follow(lobby, &mut game, &mut progress);
+                if progress.paused {
+                    return Ok(Ending::Paused);
+                }
+                if let Some(report) = ended {
+                    if lobby.results().is_some() {
+                        return Ok(Ending::Over(report));
+                    }
+                }
+            }
+            None => {
+                if let Some(report) = ended {
+                    return Ok(Ending::Over(report));
+                }
+            }
+        }
+
         seconds_behind += get_frame_time();
 
-        for _ in 0..consts::MAX_FRAMES_PER_VSYNC {
+        for _ in 0..max_frames_per_vsync {
             if seconds_behind <= 0.0 {
                 break;
             }
-            seconds_behind -= consts::TIMESTEP;
-            game.update();
+            seconds_behind -= game.arena.timestep();
+            game.update()?;
         }
 
         game.draw();
         next_frame().await;
     }
 }
+
+struct Options {
+    /// Skips the server list when set.
+    server: Option<SocketAddr>,
+    /// Skips the lobby list when set.
+    lobby: Option<shared::LobbyChoice>,
+    /// Needed to join a protected lobby, and protects the lobby if we create it.
+    password: String,
+    /// Hides the lobby from the lobby list if we create it.
+    private: bool,
+    name: String,
+    color: (u8, u8, u8),
+    /// Makes the links to other players lossy and laggy, to test rollbacks locally.
+    netsim: Option<netsim::Conditions>,
+    /// Plays offline against bots instead of connecting to a server.
+    practice: bool,
+    /// How many bots to practice against.
+    bots: usize,
+    /// How many players share this machine, each with their own keys or gamepad. Online the
+    /// ones past the first join the lobby as guests, see `LobbyClient::add_guest`, and all of
+    /// them play in one session.
+    local_players: usize,
+    difficulty: bot::Difficulty,
+    /// Watches the matches of `lobby` instead of joining it.
+    spectate: bool,
+}
+
+impl Options {
+    /// Reads `[--server HOST:PORT] [--password PASSWORD] [--private] [--name NAME]
+    /// [--color RRGGBB] [--netsim CONDITIONS] [--practice] [--bots N]
+    /// [--difficulty easy|normal|hard] [--local-players N] [--spectate] [LOBBY]` from the
+    /// command line. `--practice` plays offline against `--bots` bots, `--local-players`
+    /// players share this machine, and `--spectate` watches the matches of `LOBBY`. Without
+    /// `--server` the player picks a server found on the local network, and without `LOBBY`
+    /// one of the server's lobbies. A `LOBBY` of `new` creates a lobby with a fresh join code,
+    /// anything else joins (or creates) the lobby with that name.
+    fn from_args() -> Self {
+        let mut options = Options {
+            server: None,
+            lobby: None,
+            password: String::new(),
+            private: false,
+            name: String::new(),
+            color: (255, 161, 0),
+            netsim: None,
+            practice: false,
+            bots: 1,
+            local_players: 1,
+            difficulty: bot::Difficulty::Normal,
+            spectate: false,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => {
+                    let addr = args.next().expect("--server needs a value");
+                    options.server = Some(
+                        addr.to_socket_addrs()
+                            .ok()
+                            .and_then(|mut addrs| addrs.next())
+                            .expect("--server should be an address like 192.168.1.2:8090"),
+                    );
+                }
+                "--password" => options.password = args.next().expect("--password needs a value"),
+                "--private" => options.private = true,
+                "--name" => options.name = args.next().expect("--name needs a value"),
+                "--color" => {
+                    let hex = args.next().expect("--color needs a value");
+                    let rgb = u32::from_str_radix(hex.trim_start_matches('#'), 16)
+                        .expect("--color should be a hex RGB value like ff8800");
+                    options.color = ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+                }
+                "--netsim" => {
+                    let spec = args.next().expect("--netsim needs a value");
+                    options.netsim = Some(netsim::Conditions::parse(&spec).unwrap_or_else(|err| {
+                        panic!(
+                            "--netsim should be like latency=80,jitter=20,loss=5: {}",
+                            err
+                        )
+                    }));
+                }
+                "--practice" => options.practice = true,
+                "--bots" => {
+                    options.bots = args
+                        .next()
+                        .and_then(|bots| bots.parse().ok())
+                        .expect("--bots should be a number")
+                }
+                "--local-players" => {
+                    options.local_players = args
+                        .next()
+                        .and_then(|count| count.parse().ok())
+                        .filter(|count| (1..=consts::KEYMAPS.len()).contains(count))
+                        .expect("--local-players should be between 1 and 4")
+                }
+                "--difficulty" => {
+                    options.difficulty = args
+                        .next()
+                        .and_then(|difficulty| bot::Difficulty::parse(&difficulty))
+                        .expect("--difficulty should be easy, normal or hard")
+                }
+                "--spectate" => options.spectate = true,
+                "new" => options.lobby = Some(shared::LobbyChoice::New),
+                _ => options.lobby = Some(shared::LobbyChoice::Named(arg)),
+            }
+        }
+        if options.practice && options.local_players + options.bots > netplay::MAX_MACHINE_PLAYERS {
+            panic!(
+                "--local-players and --bots should add up to at most {}",
+                netplay::MAX_MACHINE_PLAYERS
+            );
+        }
+        if options.spectate && !matches!(options.lobby, Some(shared::LobbyChoice::Named(_))) {
+            panic!("--spectate needs the name or join code of a LOBBY");
+        }
+        options
+    }
+}
+
+#[macroquad::main("Platformer")]
+async fn main() {
+    let mut options = Options::from_args();
+
+    let map_json = load_string(MAP_PATH).await.unwrap();
+    let map_hash = shared::content_hash(map_json.as_bytes());
+    if options.practice {
+        practice(&options, &map_json).await;
+        return;
+    }
+    let server_addr = match options.server {
+        Some(server_addr) => server_addr,
+        None => match menu::choose_server(map_hash).await {
+            menu::Choice::Server(server_addr) => server_addr,
+            menu::Choice::Practice => {
+                practice(&options, &map_json).await;
+                return;
+            }
+        },
+    };
+    if options.spectate {
+        if let Some(shared::LobbyChoice::Named(code)) = options.lobby.take() {
+            let mut lobby = LobbyClient::connect(server_addr, map_hash);
+            spectate::watch(&mut lobby, &map_json, code, options.password.clone()).await;
+        }
+        return;
+    }
+    let mut connection = Connection::new(
+        server_addr,
+        map_json,
+        options.local_players,
+        options.netsim.take(),
+    );
+
+    let mut start = connection.join(&mut options).await;
+    loop {
+        if let Err(err) = play(&mut connection, start).await {
+            connection.lobby.fail(err);
+            start = connection.join(&mut options).await;
+            continue;
+        }
+        match results::show(&mut connection.lobby).await {
+            AfterMatch::Rematch => connection.lobby.set_ready(true),
+            AfterMatch::Lobby => {}
+            AfterMatch::Leave => {
+                connection.lobby.leave();
+                start = connection.join(&mut options).await;
+                continue;
+            }
+            AfterMatch::Started(next) => {
+                start = next;
+                continue;
+            }
+        }
+        start = match connection.wait_for_start().await {
+            Some(start) => start,
+            None => connection.join(&mut options).await,
+        };
+    }
+}
@@ -0,0 +1,121 @@
+use macroquad::prelude::*;
+
+use crate::discovery::LanBrowser;
+use std::net::SocketAddr;
+
+pub enum Choice {
+    Server(SocketAddr),
+    /// Play offline against bots.
+    Practice,
+}
+
+/// Lists the lobby servers found on the local network until the player picks one, types the
+/// address of another or decides to practice offline.
+pub async fn choose_server(map_hash: u64) -> Choice {
+    let mut browser = LanBrowser::new();
+    let mut selected = 0;
+    let mut address = String::new();
+    let mut address_error = false;
+    loop {
+        browser.update();
+        let servers = browser.servers();
+        selected = selected.min(servers.len().saturating_sub(1));
+        while let Some(c) = get_char_pressed() {
+            // IPv4 or IPv6 addresses, whose characters do not clash with the P to practice.
+            if c.is_ascii_hexdigit() || ".:[]".contains(c) {
+                address.push(c);
+                address_error = false;
+            }
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            address.pop();
+            address_error = false;
+        }
+        if address.is_empty() {
+            if is_key_pressed(KeyCode::Down) && selected + 1 < servers.len() {
+                selected += 1;
+            }
+            if is_key_pressed(KeyCode::Up) && selected > 0 {
+                selected -= 1;
+            }
+            if is_key_pressed(KeyCode::P) {
+                return Choice::Practice;
+            }
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            if !address.is_empty() {
+                match address.parse() {
+                    Ok(addr) => {
+                        info!("Connecting to {}", addr);
+                        return Choice::Server(addr);
+                    }
+                    Err(_) => address_error = true,
+                }
+            } else if let Some(server) = servers.get(selected) {
+                info!("Connecting to {}", server.addr);
+                return Choice::Server(server.addr);
+            }
+        }
+
+        clear_background(BLACK);
+        draw_text("Servers on your network", 20.0, 40.0, 30.0, WHITE);
+        draw_text(
+            "P to practice offline against bots",
+            20.0,
+            screen_height() - 20.0,
+            20.0,
+            GRAY,
+        );
+        draw_text(
+            &if address.is_empty() {
+                "Or type an address like 192.168.1.2:8090".to_string()
+            } else if address_error {
+                format!("Address: {} is not like 192.168.1.2:8090", address)
+            } else {
+                format!("Address: {}, Enter to connect", address)
+            },
+            20.0,
+            screen_height() - 50.0,
+            20.0,
+            if address_error { RED } else { WHITE },
+        );
+        if servers.is_empty() {
+            draw_text("Searching...", 40.0, 100.0, 30.0, GRAY);
+        } else {
+            draw_text(
+                "Up/Down to choose, Enter to connect",
+                20.0,
+                70.0,
+                20.0,
+                WHITE,
+            );
+        }
+        for (i, server) in servers.iter().enumerate() {
+            let shared::Announcement {
+                name,
+                version,
+                players,
+                map,
+                map_hash: server_map_hash,
+                ..
+            } = &server.announcement;
+            let compatible = *version == shared::PROTOCOL_VERSION && *server_map_hash == map_hash;
+            draw_text(
+                &format!(
+                    "{} {} ({}), {} players, map {}{}",
+                    if i == selected { ">" } else { " " },
+                    name,
+                    server.addr,
+                    players,
+                    map,
+                    if compatible { "" } else { " [incompatible]" }
+                ),
+                20.0,
+                110.0 + i as f32 * 30.0,
+                30.0,
+                if compatible { WHITE } else { GRAY },
+            );
+        }
+        next_frame().await;
+    }
+}
@@ -0,0 +1,86 @@
+use macroquad::prelude::*;
+use macroquad::ui::root_ui;
+
+use crate::lobby::LobbyClient;
+
+pub enum AfterMatch {
+    /// Back to the lobby, ready to play again with the same players.
+    Rematch,
+    /// Back to the lobby, not ready yet.
+    Lobby,
+    /// Leave the lobby and pick another one.
+    Leave,
+    /// The lobby's next match started before the player decided.
+    Started(shared::Start),
+}
+
+/// Waits for the server to collect every player's report, then shows who won until the player
+/// decides what to do next.
+pub async fn show(lobby: &mut LobbyClient) -> AfterMatch {
+    loop {
+        if let Some(start) = lobby.update() {
+            return AfterMatch::Started(start);
+        }
+
+        clear_background(BLACK);
+        if !lobby.draw_error() {
+            match lobby.results() {
+                None => draw_text("Waiting for the other players...", 20.0, 40.0, 30.0, WHITE),
+                Some(results) => {
+                    let headline = match &results.winner {
+                        Some(winner) => format!("{} wins!", winner),
+                        None => "Nobody survived".to_string(),
+                    };
+                    draw_text(&headline, 20.0, 40.0, 30.0, WHITE);
+                    for (i, (name, kills)) in results.scoreboard.iter().enumerate() {
+                        draw_text(
+                            &format!("{}: {} kills", name, kills),
+                            40.0,
+                            120.0 + i as f32 * 25.0,
+                            25.0,
+                            WHITE,
+                        );
+                    }
+                    if results.disputed {
+                        draw_text(
+                            "Players saw different results, these are what most of them saw",
+                            20.0,
+                            screen_height() - 15.0,
+                            20.0,
+                            YELLOW,
+                        );
+                    }
+                    if root_ui().button(vec2(20.0, 60.0), "Rematch") {
+                        return AfterMatch::Rematch;
+                    }
+                    if root_ui().button(vec2(100.0, 60.0), "Return to lobby") {
+                        return AfterMatch::Lobby;
+                    }
+                    if root_ui().button(vec2(230.0, 60.0), "Leave lobby") {
+                        return AfterMatch::Leave;
+                    }
+                }
+            }
+        }
+        next_frame().await;
+    }
+}
+
+/// Shows who won a practice match. Returns whether the player wants another one.
+pub async fn show_practice(winner: Option<&str>) -> bool {
+    loop {
+        clear_background(BLACK);
+        let headline = match winner {
+            Some(winner) => format!("{} wins!", winner),
+            None => "Nobody survived".to_string(),
+        };
+        draw_text(&headline, 20.0, 40.0, 30.0, WHITE);
+        if root_ui().button(vec2(20.0, 60.0), "Play again") {
+            return true;
+        }
+        if root_ui().button(vec2(110.0, 60.0), "Quit") {
+            return false;
+        }
+        next_frame().await;
+    }
+}
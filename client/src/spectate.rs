@@ -0,0 +1,246 @@
+//! Watching a lobby's matches without playing in them. The lobby server relays every player's
+//! inputs, and we simulate them a little behind the players, so that the next batch usually
+//! arrives before we need it.
+
+use macroquad::prelude::*;
+
+use crate::arena::{Arena, MAP_HEIGHT, MAP_WIDTH};
+use crate::lobby::LobbyClient;
+use sim::{Input, PLAYER_SIZE};
+
+/// How far behind the newest relayed inputs we simulate. Players send their inputs in batches
+/// and the server relays them on its own timer, so this covers a few of both.
+const DELAY_FRAMES: usize = 3 * shared::INPUT_BATCH_FRAMES;
+/// Map pixels per second the camera pans at, without zoom.
+const PAN_SPEED: f32 = 160.0;
+const MAX_ZOOM: f32 = 4.0;
+/// Digit keys follow the player of their `shared::Start` index, plus one.
+const FOLLOW_KEYS: [KeyCode; 8] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+];
+
+/// Arrows pan and Page Up/Down zoom. The digit keys follow a player and 0 shows the whole map
+/// again.
+struct FreeCamera {
+    center: Vec2,
+    zoom: f32,
+    /// `sim::PlayerState::id` of the player the camera follows.
+    follow: Option<u32>,
+}
+
+impl FreeCamera {
+    fn new() -> Self {
+        Self {
+            center: vec2(MAP_WIDTH / 2.0, MAP_HEIGHT / 2.0),
+            zoom: 1.0,
+            follow: None,
+        }
+    }
+
+    fn update(&mut self, arena: &mut Arena) {
+        let dt = get_frame_time();
+        let mut pan = vec2(0.0, 0.0);
+        if is_key_down(KeyCode::Left) {
+            pan.x -= 1.0;
+        }
+        if is_key_down(KeyCode::Right) {
+            pan.x += 1.0;
+        }
+        if is_key_down(KeyCode::Up) {
+            pan.y -= 1.0;
+        }
+        if is_key_down(KeyCode::Down) {
+            pan.y += 1.0;
+        }
+        if pan != vec2(0.0, 0.0) {
+            self.follow = None;
+            self.center += pan * PAN_SPEED * dt / self.zoom;
+        }
+        if is_key_down(KeyCode::PageUp) {
+            self.zoom *= 1.0 + dt;
+        }
+        if is_key_down(KeyCode::PageDown) {
+            self.zoom /= 1.0 + dt;
+        }
+        self.zoom = self.zoom.clamp(1.0, MAX_ZOOM);
+        for (id, key) in FOLLOW_KEYS.iter().enumerate().take(arena.players.len()) {
+            if is_key_pressed(*key) {
+                self.follow = Some(id as u32);
+            }
+        }
+        if is_key_pressed(KeyCode::Key0) {
+            *self = Self::new();
+        }
+
+        let followed = self.follow.and_then(|id| {
+            arena
+                .sim
+                .state
+                .players
+                .iter()
+                .find(|player| player.id == id)
+        });
+        if let Some(player) = followed {
+            let half = PLAYER_SIZE as f32 / 2.0;
+            self.center = vec2(player.actor.x as f32 + half, player.actor.y as f32 + half);
+        }
+
+        let (width, height) = (MAP_WIDTH / self.zoom, MAP_HEIGHT / self.zoom);
+        arena.camera = Camera2D::from_display_rect(Rect::new(
+            self.center.x - width / 2.0,
+            self.center.y - height / 2.0,
+            width,
+            height,
+        ));
+    }
+}
+
+/// Watches the matches of the lobby named `code` until the window closes. `lobby` must not be
+/// in a lobby, and `map_json` is the map it was connected with.
+pub async fn watch(lobby: &mut LobbyClient, map_json: &str, code: String, password: String) {
+    lobby.spectate(code, password);
+    let mut camera = FreeCamera::new();
+    let mut start = wait_for_start(lobby).await;
+    loop {
+        let mut arena = match Arena::new(&start, map_json).await {
+            Ok(arena) => arena,
+            Err(err) => {
+                error!("Can not watch the match: {:?}", err);
+                return;
+            }
+        };
+        start = watch_match(lobby, &mut arena, &mut camera).await;
+    }
+}
+
+async fn wait_for_start(lobby: &mut LobbyClient) -> shared::Start {
+    loop {
+        if let Some(start) = lobby.update() {
+            return start;
+        }
+        clear_background(BLACK);
+        if !lobby.draw_error() {
+            draw_text("Waiting for the next match...", 20.0, 40.0, 30.0, WHITE);
+        }
+        next_frame().await;
+    }
+}
+
+/// Simulates the match as its inputs arrive, then shows who won until the next match starts.
+/// Returns the next match's roster.
+async fn watch_match(
+    lobby: &mut LobbyClient,
+    arena: &mut Arena,
+    camera: &mut FreeCamera,
+) -> shared::Start {
+    // Matches handed off to a new roster resume from a later frame.
+    let base = arena.sim.state.frame as usize;
+    let mut inputs: Vec<Vec<Input>> = vec![vec![]; arena.players.len()];
+    let everyone: Vec<u32> = (0..arena.players.len() as u32).collect();
+    let mut seconds_behind = 0.0;
+
+    loop {
+        if let Some(start) = lobby.update() {
+            return start;
+        }
+        for batch in lobby.take_inputs() {
+            if let Some(recorded) = inputs.get_mut(batch.player as usize) {
+                // The server relays every input once and in order.
+                if batch.first_frame as usize == base + recorded.len() {
+                    recorded.extend(
+                        batch
+                            .inputs
+                            .iter()
+                            .map(|bits| Input::from_bits_truncate(*bits)),
+                    );
+                }
+            }
+        }
+
+        // Only players still alive need inputs, and once the match is over nothing more is
+        // coming to wait for.
+        let available = arena
+            .sim
+            .state
+            .players
+            .iter()
+            .map(|player| base + inputs[player.id as usize].len())
+            .min()
+            .unwrap_or(base);
+        let target = if lobby.results().is_some() {
+            available
+        } else {
+            available.saturating_sub(DELAY_FRAMES)
+        };
+
+        // Catch up at once when joining a match in progress.
+        if arena.sim.state.frame as usize + 2 * DELAY_FRAMES < target {
+            while arena.sim.outcome().is_none() && (arena.sim.state.frame as usize) < target {
+                advance(arena, base, &inputs);
+            }
+        }
+        seconds_behind += get_frame_time();
+        while seconds_behind >= arena.timestep()
+            && arena.sim.outcome().is_none()
+            && (arena.sim.state.frame as usize) < target
+        {
+            seconds_behind -= arena.timestep();
+            advance(arena, base, &inputs);
+        }
+        // Waiting for inputs does not earn time to rush through them later.
+        seconds_behind = seconds_behind.min(arena.timestep());
+
+        camera.update(arena);
+        arena.draw(&everyone);
+        if !lobby.draw_error() {
+            let over = arena.sim.state.frame as usize >= target && lobby.results().is_some();
+            let headline = match (arena.sim.outcome(), lobby.results()) {
+                (Some(Some(winner)), _) => {
+                    Some(format!("{} wins!", arena.players[winner as usize].name))
+                }
+                (Some(None), _) => Some("Nobody survived".to_string()),
+                // The match can also end before our simulation sees an outcome, e.g. when
+                // players leave.
+                (None, Some(results)) if over => Some(match &results.winner {
+                    Some(winner) => format!("{} wins!", winner),
+                    None => "Nobody survived".to_string(),
+                }),
+                (None, _) => None,
+            };
+            match headline {
+                Some(headline) => {
+                    draw_text(&headline, 20.0, screen_height() - 40.0, 30.0, WHITE);
+                    draw_text(
+                        "Waiting for the next match...",
+                        20.0,
+                        screen_height() - 15.0,
+                        20.0,
+                        WHITE,
+                    );
+                }
+                None => draw_text(
+                    "Spectating. Arrows pan, Page Up/Down zoom, 1-8 follow a player, 0 resets",
+                    20.0,
+                    screen_height() - 15.0,
+                    20.0,
+                    WHITE,
+                ),
+            }
+        }
+        next_frame().await;
+    }
+}
+
+/// `inputs` start at frame `base`.
+fn advance(arena: &mut Arena, base: usize, inputs: &[Vec<Input>]) {
+    let frame = arena.sim.state.frame as usize - base;
+    let hits = arena.sim.advance(|id| inputs[id as usize][frame]);
+    arena.explode(hits);
+}
@@ -0,0 +1,290 @@
+//! Plays a match over a backroll session, without drawing anything: feeds it the inputs of the
+//! players on this machine, runs the simulation as it asks, records our inputs for the lobby
+//! server and tells when the match is over. The game, the bots and the test harness all play
+//! through this.
+
+use backroll::{
+    command::{Command, Commands},
+    BackrollError, Event, P2PSession, Player as BackrollPlayer,
+    PlayerHandle as BackrollPlayerHandle,
+};
+use backroll_transport::Peer;
+use bevy_tasks::TaskPool;
+use bytemuck::{Pod, Zeroable};
+use sim::{Fixed, Input, Sim};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+/// Backroll's default prediction window: a peer plays at most this many frames ahead of the
+/// last frame it has everyone's inputs for. So once we played a frame, the one this many
+/// frames earlier is confirmed and no rollback changes it anymore.
+pub const PREDICTION_WINDOW: u32 = 8;
+/// How many of the last frames `Session::history` keeps, well beyond `PREDICTION_WINDOW`.
+const HISTORY_FRAMES: usize = 32;
+/// How many players one machine seats at most, as many as a lobby holds.
+pub const MAX_MACHINE_PLAYERS: usize = 8;
+
+/// The inputs of the players at one machine, in the order they appear in the match.
+/// Backroll sends a local player's input to every remote peer, who file it under the one
+/// player they know from that peer, so each machine plays as one backroll player.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Zeroable, Pod)]
+pub struct MachineInputs([Input; MAX_MACHINE_PLAYERS]);
+
+pub struct BackrollConfig;
+
+impl backroll::Config for BackrollConfig {
+    type Input = MachineInputs;
+    type State = sim::State;
+}
+
+/// Who plays a match player.
+pub enum Seat {
+    /// Someone on this machine, whose input `Session::update` asks for.
+    Local,
+    /// Someone at the machine with this address, which may seat other players too.
+    Remote(SocketAddr),
+}
+
+pub struct Session {
+    session: P2PSession<BackrollConfig>,
+    /// The backroll player of each player's machine and where the player is in its
+    /// `MachineInputs`, indexed by `sim::PlayerState::id`.
+    seats: Vec<(BackrollPlayerHandle, usize)>,
+    /// The backroll player of this machine, if it seats anyone.
+    local_handle: Option<BackrollPlayerHandle>,
+    /// Ids of the players on this machine, in `shared::Start` order.
+    local_players: Vec<u32>,
+    frames_to_stall: u8,
+    /// Inputs of each local player not yet sent to the lobby server, as `Input` bits,
+    /// starting at frame `recorded_from`.
+    recorded: Vec<Vec<u8>>,
+    recorded_from: u32,
+    /// Events not yet taken by `take_events`, other than the time syncs handled here.
+    events: Vec<Event>,
+    /// `Sim::report` after each of the last frames, oldest first, as last simulated, and
+    /// whether the match was over then.
+    history: VecDeque<(shared::MatchReport, bool)>,
+    /// Our report of the first confirmed frame the match was over on.
+    ended: Option<shared::MatchReport>,
+    /// Frame that `update` plays no further than, e.g. for a handoff.
+    stop_at: Option<u32>,
+}
+
+impl Session {
+    /// `seats` has who plays each player of the match, which `sim` is at the start of.
+    /// `connect` connects to the machine at an address, given the id of its first player.
+    /// Players past `MAX_MACHINE_PLAYERS` at one machine stand still.
+    pub fn new(
+        task_pool: &TaskPool,
+        seats: Vec<Seat>,
+        sim: &Sim,
+        mut connect: impl FnMut(u32, SocketAddr) -> Peer,
+    ) -> Self {
+        let mut builder = P2PSession::build();
+        // The address of each machine, `None` for ours, with its backroll player and how many
+        // players it seats so far.
+        let mut machines: Vec<(Option<SocketAddr>, BackrollPlayerHandle, usize)> = vec![];
+        let mut player_seats = vec![];
+        let mut local_players = vec![];
+        for (id, seat) in seats.into_iter().enumerate() {
+            let id = id as u32;
+            let addr = match seat {
+                Seat::Local => {
+                    local_players.push(id);
+                    None
+                }
+                Seat::Remote(addr) => Some(addr),
+            };
+            let machine = match machines.iter().position(|(other, ..)| *other == addr) {
+                Some(machine) => machine,
+                None => {
+                    let handle = builder.add_player(match addr {
+                        None => BackrollPlayer::Local,
+                        Some(addr) => BackrollPlayer::Remote(connect(id, addr)),
+                    });
+                    machines.push((addr, handle, 0));
+                    machines.len() - 1
+                }
+            };
+            let (_, handle, seated) = &mut machines[machine];
+            player_seats.push((*handle, *seated));
+            *seated += 1;
+        }
+        let local_handle = machines
+            .iter()
+            .find(|(addr, ..)| addr.is_none())
+            .map(|(_, handle, _)| *handle);
+        Self {
+            session: builder.start(task_pool.clone()).unwrap(),
+            seats: player_seats,
+            local_handle,
+            recorded: vec![vec![]; local_players.len()],
+            local_players,
+            frames_to_stall: 0,
+            recorded_from: sim.state.frame,
+            events: vec![],
+            history: VecDeque::new(),
+            ended: None,
+            stop_at: None,
+        }
+    }
+
+    pub fn local_players(&self) -> &[u32] {
+        &self.local_players
+    }
+
+    /// The `sim::PlayerState::id`s of the players at the machine behind a backroll handle.
+    pub fn player_ids(&self, handle: BackrollPlayerHandle) -> Vec<u32> {
+        (0..self.seats.len() as u32)
+            .filter(|id| self.seats[*id as usize].0 == handle)
+            .collect()
+    }
+
+    /// Talks to the other peers and simulates again whatever they corrected, without playing a
+    /// new frame. Returns where bullets hit, as `Sim::advance` does.
+    pub fn poll(&mut self, sim: &mut Sim) -> Vec<(Fixed, Fixed)> {
+        let commands = self.session.poll();
+        self.run_commands(sim, commands)
+    }
+
+    /// Polls, then plays the next frame with `input` of each local player, unless the other
+    /// peers are behind, we reached `stop_at` or the match `ended`. Returns where bullets hit,
+    /// as `Sim::advance` does. Errors other than waiting for the other peers or for a player
+    /// who left mean the session is broken.
+    pub fn update(
+        &mut self,
+        sim: &mut Sim,
+        mut input: impl FnMut(u32, &Sim) -> Input,
+    ) -> Result<Vec<(Fixed, Fixed)>, BackrollError> {
+        let mut hits = self.poll(sim);
+        if self.frames_to_stall > 0 {
+            self.frames_to_stall -= 1;
+            return Ok(hits);
+        }
+        if !self.session.is_synchronized() || self.stopped(sim) || self.ended.is_some() {
+            return Ok(hits);
+        }
+        let mut inputs = MachineInputs::zeroed();
+        for id in &self.local_players {
+            if let Some(packed) = inputs.0.get_mut(self.seats[*id as usize].1) {
+                *packed = input(*id, sim);
+            }
+        }
+        if let Some(handle) = self.local_handle {
+            match self.session.add_local_input(handle, inputs) {
+                Ok(()) => {}
+                Err(BackrollError::ReachedPredictionBarrier)
+                | Err(BackrollError::PlayerDisconnected(_)) => return Ok(hits),
+                Err(err) => return Err(err),
+            }
+        }
+        for (id, recorded) in self.local_players.iter().zip(&mut self.recorded) {
+            let bits = inputs
+                .0
+                .get(self.seats[*id as usize].1)
+                .map_or(0, |input| input.bits());
+            recorded.push(bits);
+        }
+        let commands = self.session.advance_frame();
+        hits.extend(self.run_commands(sim, commands));
+        let confirmed = sim.state.frame.saturating_sub(PREDICTION_WINDOW);
+        if let Some((report, true)) = self
+            .history
+            .iter()
+            .find(|(report, _)| report.frame == confirmed)
+        {
+            self.ended = Some(report.clone());
+        }
+        Ok(hits)
+    }
+
+    /// Our report of the match once it is over on a frame every peer confirmed, so that they
+    /// all report the same frame. The predicted frames after it are not played anymore, but
+    /// keep the session updating until the lobby server has everyone's report: the other
+    /// peers may still need our inputs to confirm the end too.
+    pub fn ended(&self) -> Option<&shared::MatchReport> {
+        self.ended.as_ref()
+    }
+
+    /// Plays no further than `frame`. The session keeps talking to the other peers there, who
+    /// may still need our inputs to get there too.
+    pub fn stop_at(&mut self, frame: u32) {
+        self.stop_at = Some(frame);
+    }
+
+    /// Whether `sim` reached the frame given to `stop_at`.
+    pub fn stopped(&self, sim: &Sim) -> bool {
+        matches!(self.stop_at, Some(frame) if sim.state.frame >= frame)
+    }
+
+    /// Takes the recorded inputs of each local player, in `local_players` order, once there
+    /// are `shared::INPUT_BATCH_FRAMES` of them, or any at all with `flush`.
+    pub fn take_recorded(&mut self, flush: bool) -> Vec<shared::Inputs> {
+        let count = self.recorded.first().map_or(0, Vec::len);
+        if count == 0 || (!flush && count < shared::INPUT_BATCH_FRAMES) {
+            return vec![];
+        }
+        let first_frame = self.recorded_from;
+        self.recorded_from += count as u32;
+        self.local_players
+            .iter()
+            .zip(&mut self.recorded)
+            .map(|(id, recorded)| shared::Inputs {
+                player: *id,
+                first_frame,
+                inputs: std::mem::take(recorded),
+            })
+            .collect()
+    }
+
+    /// `Sim::report` after each of the last frames, oldest first. Frames a rollback simulated
+    /// again show their latest result.
+    pub fn history(&self) -> impl Iterator<Item = &shared::MatchReport> {
+        self.history.iter().map(|(report, _)| report)
+    }
+
+    /// Connection events since the last call, e.g. remote players disconnecting.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn run_commands(
+        &mut self,
+        sim: &mut Sim,
+        commands: Commands<BackrollConfig>,
+    ) -> Vec<(Fixed, Fixed)> {
+        let mut hits = vec![];
+        for command in commands {
+            match command {
+                Command::Save(save_state) => save_state.save(sim.state.clone()),
+                Command::Load(load_state) => sim.state = load_state.load().clone(),
+                Command::AdvanceFrame(input) => {
+                    let seats = &self.seats;
+                    // Disconnected players have no input, see `sim::Input`.
+                    hits.extend(sim.advance(|id| {
+                        let (handle, index) = seats[id as usize];
+                        input.get(handle).map_or(Input::empty(), |inputs| {
+                            inputs.0.get(index).copied().unwrap_or_else(Input::empty)
+                        })
+                    }));
+                    let frame = sim.state.frame;
+                    while matches!(self.history.back(), Some((report, _)) if report.frame >= frame)
+                    {
+                        self.history.pop_back();
+                    }
+                    if self.history.len() == HISTORY_FRAMES {
+                        self.history.pop_front();
+                    }
+                    self.history
+                        .push_back((sim.report(), sim.outcome().is_some()));
+                }
+                Command::Event(Event::TimeSync { frames_ahead }) => {
+                    self.frames_to_stall = frames_ahead;
+                }
+                Command::Event(event) => self.events.push(event),
+            }
+        }
+        hits
+    }
+}
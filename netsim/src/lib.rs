@@ -0,0 +1,116 @@
+//! Bad network conditions for testing rollbacks on localhost, where the round trip takes no
+//! time. `wrap` puts a lossy, laggy link between a session and the `Peer` of a remote player.
+
+use backroll_transport::Peer;
+use bevy_tasks::TaskPool;
+use futures_timer::Delay;
+use shared::rng::Rng;
+use std::time::Duration;
+
+/// Applied to each direction of a link separately, so the round trip gets twice the latency.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Conditions {
+    pub latency: Duration,
+    /// Each packet's delay varies by up to this much either way.
+    pub jitter: Duration,
+    /// Percent of packets that are dropped.
+    pub loss: u32,
+    /// Percent of packets that are sent twice.
+    pub duplicate: u32,
+    /// Percent of packets that are held back long enough for later ones to overtake them.
+    pub reorder: u32,
+}
+
+impl Conditions {
+    /// Parses comma separated `key=value` pairs, e.g. `latency=80,jitter=20,loss=5`. Times are
+    /// in milliseconds; `loss`, `duplicate` and `reorder` are percentages.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut conditions = Conditions::default();
+        for pair in spec.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => return Err(format!("expected key=value, got {}", pair)),
+            };
+            let value: u32 = value
+                .parse()
+                .map_err(|_| format!("{} should be a number", key))?;
+            match key {
+                "latency" => conditions.latency = Duration::from_millis(value as u64),
+                "jitter" => conditions.jitter = Duration::from_millis(value as u64),
+                "loss" | "duplicate" | "reorder" if value > 100 => {
+                    return Err(format!("{} is a percentage", key))
+                }
+                "loss" => conditions.loss = value,
+                "duplicate" => conditions.duplicate = value,
+                "reorder" => conditions.reorder = value,
+                _ => return Err(format!("unknown network condition {}", key)),
+            }
+        }
+        Ok(conditions)
+    }
+
+    /// How long to hold a packet back, or `None` to drop it.
+    pub fn delay(&self, rng: &mut Rng) -> Option<Duration> {
+        if self.loss > 0 && rng.chance(self.loss) {
+            return None;
+        }
+        let jitter = self.jitter.as_millis() as u32;
+        let mut delay = (self.latency + Duration::from_millis(rng.below(2 * jitter + 1) as u64))
+            .checked_sub(self.jitter)
+            .unwrap_or_default();
+        if self.reorder > 0 && rng.chance(self.reorder) {
+            delay += self.latency.max(Duration::from_millis(20));
+        }
+        Some(delay)
+    }
+}
+
+/// Returns a `Peer` to use instead of `peer`, whose traffic suffers `conditions` in both
+/// directions. `seed` makes the dropped and delayed packets repeatable.
+pub fn wrap(task_pool: &TaskPool, peer: Peer, conditions: Conditions, seed: u64) -> Peer {
+    let (session_side, link_side) = Peer::create_unbounded();
+    forward(
+        task_pool,
+        link_side.clone(),
+        peer.clone(),
+        conditions.clone(),
+        Rng::new(seed),
+    );
+    forward(task_pool, peer, link_side, conditions, Rng::new(!seed));
+    session_side
+}
+
+/// Passes packets from `from` on to `to` until either side disconnects.
+fn forward(task_pool: &TaskPool, from: Peer, to: Peer, conditions: Conditions, mut rng: Rng) {
+    let spawner = task_pool.clone();
+    task_pool
+        .spawn(async move {
+            while let Ok(packet) = from.recv().await {
+                let copies = if conditions.duplicate > 0 && rng.chance(conditions.duplicate) {
+                    2
+                } else {
+                    1
+                };
+                for _ in 0..copies {
+                    let delay = match conditions.delay(&mut rng) {
+                        Some(delay) => delay,
+                        None => continue,
+                    };
+                    let to = to.clone();
+                    let packet = packet.clone();
+                    spawner
+                        .spawn(async move {
+                            Delay::new(delay).await;
+                            let _ = to.send(packet).await;
+                        })
+                        .detach();
+                }
+                if !to.is_connected() {
+                    break;
+                }
+            }
+            from.disconnect();
+            to.disconnect();
+        })
+        .detach();
+}
@@ -0,0 +1,134 @@
+//! Parsing network conditions and what they do to single packets.
+
+use netsim::Conditions;
+use shared::rng::Rng;
+use std::time::Duration;
+
+const PACKETS: usize = 1000;
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+/// `conditions.delay` for `PACKETS` packets.
+fn delays(conditions: &Conditions) -> Vec<Option<Duration>> {
+    let mut rng = Rng::new(7);
+    (0..PACKETS).map(|_| conditions.delay(&mut rng)).collect()
+}
+
+/// `delays` of packets that are all expected to arrive.
+fn delays_of(conditions: &Conditions) -> impl Iterator<Item = Duration> {
+    delays(conditions).into_iter().map(Option::unwrap)
+}
+
+#[test]
+fn parses_conditions() {
+    assert_eq!(
+        Conditions::parse("latency=80,jitter=20,loss=5,duplicate=2,reorder=10"),
+        Ok(Conditions {
+            latency: ms(80),
+            jitter: ms(20),
+            loss: 5,
+            duplicate: 2,
+            reorder: 10,
+        })
+    );
+    assert_eq!(Conditions::parse(""), Ok(Conditions::default()));
+    assert_eq!(
+        Conditions::parse("loss=100,"),
+        Ok(Conditions {
+            loss: 100,
+            ..Conditions::default()
+        })
+    );
+}
+
+#[test]
+fn rejects_invalid_conditions() {
+    for spec in &[
+        "latency",
+        "latency=fast",
+        "loss=101",
+        "latency=-5",
+        "bandwidth=10",
+    ] {
+        assert!(Conditions::parse(spec).is_err(), "{}", spec);
+    }
+}
+
+#[test]
+fn perfect_links_pass_packets_right_away() {
+    assert!(delays(&Conditions::default())
+        .iter()
+        .all(|delay| *delay == Some(ms(0))));
+}
+
+#[test]
+fn lost_packets_are_dropped() {
+    let lossy = Conditions {
+        loss: 50,
+        ..Conditions::default()
+    };
+    let dropped = delays(&lossy)
+        .iter()
+        .filter(|delay| delay.is_none())
+        .count();
+    assert!((400..600).contains(&dropped), "{} dropped", dropped);
+
+    let dead = Conditions {
+        loss: 100,
+        ..Conditions::default()
+    };
+    assert!(delays(&dead).iter().all(Option::is_none));
+}
+
+#[test]
+fn jitter_varies_the_latency_both_ways() {
+    let conditions = Conditions {
+        latency: ms(80),
+        jitter: ms(20),
+        ..Conditions::default()
+    };
+    let delays: Vec<Duration> = delays_of(&conditions).collect();
+    assert!(delays
+        .iter()
+        .all(|delay| (ms(60)..=ms(100)).contains(delay)));
+    assert!(delays.iter().any(|delay| *delay < ms(70)));
+    assert!(delays.iter().any(|delay| *delay > ms(90)));
+
+    // Packets jittered to before they were sent go out right away.
+    let conditions = Conditions {
+        latency: ms(5),
+        jitter: ms(20),
+        ..Conditions::default()
+    };
+    let delays: Vec<Duration> = delays_of(&conditions).collect();
+    assert!(delays.iter().all(|delay| *delay <= ms(25)));
+    assert!(delays.iter().any(|delay| *delay == ms(0)));
+}
+
+#[test]
+fn reordered_packets_are_held_back() {
+    let conditions = Conditions {
+        latency: ms(80),
+        reorder: 100,
+        ..Conditions::default()
+    };
+    assert!(delays_of(&conditions).all(|delay| delay == ms(160)));
+
+    // Even without latency, long enough for the next packets to overtake them.
+    let conditions = Conditions {
+        reorder: 100,
+        ..Conditions::default()
+    };
+    assert!(delays_of(&conditions).all(|delay| delay == ms(20)));
+
+    let conditions = Conditions {
+        reorder: 30,
+        ..Conditions::default()
+    };
+    let held = delays_of(&conditions)
+        .filter(|delay| *delay > ms(0))
+        .count();
+    assert!((200..400).contains(&held), "{} held back", held);
+}
@@ -0,0 +1,196 @@
+//! Admin commands, read line by line from the server's stdin.
+
+use super::{Lobby, Server, MIN_MATCH_PLAYERS};
+
+use std::io::BufRead;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+const HELP: &str = "\
+commands:
+  lobbies                          list lobbies
+  players <lobby>                  list the players in a lobby
+  start <lobby>                    start a lobby's match right away
+  kick <lobby> <slot>              remove a player from a lobby
+  ban <ip>                         drop every connection from <ip> and refuse new ones
+  unban <ip>
+  bans                             list banned addresses
+  set <lobby> <setting> <value>    change min_players, max_players, auto_start, tick_rate
+                                   or score_limit; the map is the server's
+  help";
+
+pub fn run(server: &Server) {
+    println!("Admin console ready, type help for commands");
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+        match execute(server, &args) {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("error: {}", err),
+        }
+    }
+}
+
+fn execute(server: &Server, args: &[&str]) -> Result<String, String> {
+    match args {
+        ["lobbies"] => {
+            let lobbies = server.lobbies.read().unwrap();
+            let mut codes: Vec<&String> = lobbies.lobbies.keys().collect();
+            codes.sort();
+            let lines: Vec<String> = codes
+                .into_iter()
+                .map(|code| {
+                    let lobby = lobbies.lobbies[code].read().unwrap();
+                    format!(
+                        "{}: {}/{} players{}{}{}{}",
+                        code,
+                        lobby.players().count(),
+                        server.max_players(&lobby),
+                        if lobby.password.is_some() {
+                            ", password"
+                        } else {
+                            ""
+                        },
+                        if lobby.private { ", private" } else { "" },
+                        if lobby.started { ", playing" } else { "" },
+                        if lobby.countdown_started.is_some() {
+                            ", counting down"
+                        } else {
+                            ""
+                        }
+                    )
+                })
+                .collect();
+            Ok(if lines.is_empty() {
+                "no lobbies".to_string()
+            } else {
+                lines.join("\n")
+            })
+        }
+        ["players", code] => {
+            let lobby = find_lobby(server, code)?;
+            let lobby = lobby.read().unwrap();
+            let host = lobby.host();
+            let lines: Vec<String> = lobby
+                .players
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, player)| {
+                    player.as_ref().map(|player| {
+                        format!(
+                            "{}: {} {}{}{}",
+                            slot,
+                            player.name,
+                            player.addr,
+                            if player.ready { " ready" } else { "" },
+                            if host == Some(slot) { " host" } else { "" }
+                        )
+                    })
+                })
+                .collect();
+            Ok(lines.join("\n"))
+        }
+        ["start", code] => {
+            let lobby = find_lobby(server, code)?;
+            server
+                .start_lobby(&lobby)
+                .map_err(|err| format!("lobby {} {}", code, err))?;
+            Ok(format!("started lobby {}", code))
+        }
+        ["kick", code, slot] => {
+            let slot: usize = slot.parse().map_err(|_| "slot should be a number")?;
+            let lobby = find_lobby(server, code)?;
+            let mut lobby = lobby.write().unwrap();
+            let player = lobby
+                .players
+                .get_mut(slot)
+                .and_then(Option::as_mut)
+                .ok_or_else(|| format!("no player in slot {}", slot))?;
+            player.kicked = true;
+            Ok(format!("kicked {}", player.name))
+        }
+        ["ban", ip] => {
+            let ip: IpAddr = ip.parse().map_err(|_| "invalid ip address")?;
+            let dropped = server.gate.ban(ip);
+            Ok(format!("banned {}, dropped {} connections", ip, dropped))
+        }
+        ["unban", ip] => {
+            let ip: IpAddr = ip.parse().map_err(|_| "invalid ip address")?;
+            if server.gate.unban(ip) {
+                Ok(format!("unbanned {}", ip))
+            } else {
+                Err(format!("{} is not banned", ip))
+            }
+        }
+        ["bans"] => {
+            let bans: Vec<String> = server.gate.bans().iter().map(ToString::to_string).collect();
+            Ok(if bans.is_empty() {
+                "no bans".to_string()
+            } else {
+                bans.join("\n")
+            })
+        }
+        ["set", code, setting, value] => {
+            let lobby = find_lobby(server, code)?;
+            let mut lobby = lobby.write().unwrap();
+            match *setting {
+                "min_players" => {
+                    let min_players: usize = value.parse().map_err(|_| "expected a number")?;
+                    if min_players < MIN_MATCH_PLAYERS {
+                        return Err(format!(
+                            "min_players has to be at least {}",
+                            MIN_MATCH_PLAYERS
+                        ));
+                    }
+                    lobby.settings.min_players = min_players
+                }
+                "max_players" => {
+                    lobby.settings.max_players = value.parse().map_err(|_| "expected a number")?
+                }
+                "auto_start" => {
+                    lobby.settings.auto_start =
+                        value.parse().map_err(|_| "expected true or false")?
+                }
+                "tick_rate" => {
+                    let tick_rate: u32 = value.parse().map_err(|_| "expected a number")?;
+                    if tick_rate == 0 {
+                        return Err("tick_rate has to be positive".to_string());
+                    }
+                    lobby.settings.tick_rate = tick_rate
+                }
+                "score_limit" => {
+                    lobby.settings.score_limit = value.parse().map_err(|_| "expected a number")?
+                }
+                // Clients check the server's map when connecting and only have that one.
+                "map" => {
+                    return Err("every lobby plays the server's map, serve another one \
+                                from another server"
+                        .to_string())
+                }
+                _ => return Err(format!("unknown setting {}", setting)),
+            }
+            lobby.roster_changed();
+            Ok(format!("{} = {} in lobby {}", setting, value, code))
+        }
+        ["help"] => Ok(HELP.to_string()),
+        _ => Err(format!("unknown command, try help\n{}", HELP)),
+    }
+}
+
+fn find_lobby(server: &Server, code: &str) -> Result<Arc<RwLock<Lobby>>, String> {
+    server
+        .lobbies
+        .read()
+        .unwrap()
+        .lobbies
+        .get(code)
+        .cloned()
+        .ok_or_else(|| format!("no lobby {}", code))
+}
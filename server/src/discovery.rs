@@ -0,0 +1,55 @@
+//! Answers `shared::Discovery::Probe` broadcasts so that clients on the local network can list
+//! this server.
+
+use super::Server;
+
+use macroquad::prelude::*;
+use nanoserde::{DeBin, SerBin};
+use std::net::{Ipv4Addr, UdpSocket};
+
+pub fn run(server: &Server) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, shared::DISCOVERY_PORT)) {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!(
+                "Could not listen for discovery probes on port {}: {}",
+                shared::DISCOVERY_PORT,
+                err
+            );
+            return;
+        }
+    };
+    let mut buf = [0; 64];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("Failed to receive a discovery probe: {}", err);
+                continue;
+            }
+        };
+        match DeBin::deserialize_bin(&buf[..len]) {
+            Ok(shared::Discovery::Probe) => {}
+            _ => continue,
+        }
+        let players = server
+            .lobbies
+            .read()
+            .unwrap()
+            .lobbies
+            .values()
+            .map(|lobby| lobby.read().unwrap().players().count() as u32)
+            .sum();
+        let announcement = shared::Discovery::Announce(shared::Announcement {
+            name: server.name.clone(),
+            port: super::TCP_PORT,
+            version: shared::PROTOCOL_VERSION,
+            players,
+            map: server.map.name.clone(),
+            map_hash: server.map.hash,
+        });
+        if let Err(err) = socket.send_to(&SerBin::serialize_bin(&announcement), from) {
+            warn!("Failed to answer a discovery probe from {}: {}", from, err);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Accepts lobby connections in front of quad-net, whose server does not tell us the address a
+//! connection comes from. Connections from banned addresses are refused, the others are
+//! forwarded to quad-net listening on the loopback interface.
+//!
+//! quad-net's `SocketHandle` hides its stream and its `listen` binds addresses itself, so the
+//! bans can not be checked inside it. Forwarding costs two threads per connection, next to the
+//! one quad-net spends on it, which is fine for the few dozen players a lobby server holds.
+
+use macroquad::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long a connection waits for quad-net to listen, which it starts doing on its own thread.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where quad-net listens on the loopback interface.
+#[derive(Clone, Copy)]
+pub struct InnerAddrs {
+    pub tcp: SocketAddr,
+    pub ws: SocketAddr,
+}
+
+impl InnerAddrs {
+    /// Finds two ports nothing listens on yet. Another program may take them before quad-net
+    /// does, so it has to start over on new ones if it can not listen.
+    pub fn find() -> io::Result<Self> {
+        let port = || {
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).and_then(|listener| listener.local_addr())
+        };
+        Ok(Self {
+            tcp: port()?,
+            ws: port()?,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Gate {
+    bans: RwLock<HashSet<IpAddr>>,
+    /// Where to forward connections to, `None` until quad-net listens.
+    inner: RwLock<Option<InnerAddrs>>,
+    /// Every forwarded connection by id, with the address it comes from and our end of it.
+    open: Mutex<HashMap<u64, (IpAddr, TcpStream)>>,
+    next_id: AtomicU64,
+}
+
+impl Gate {
+    /// Refuses new connections from `ip` and drops the open ones. Returns how many it dropped.
+    pub fn ban(&self, ip: IpAddr) -> usize {
+        self.bans.write().unwrap().insert(ip);
+        let open = self.open.lock().unwrap();
+        let mut dropped = 0;
+        for (from, stream) in open.values() {
+            if *from == ip {
+                let _ = stream.shutdown(Shutdown::Both);
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+
+    /// Returns whether `ip` was banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.bans.write().unwrap().remove(&ip)
+    }
+
+    pub fn bans(&self) -> Vec<IpAddr> {
+        let mut bans: Vec<IpAddr> = self.bans.read().unwrap().iter().copied().collect();
+        bans.sort();
+        bans
+    }
+
+    /// Forwards connections to `inner` from now on.
+    pub fn set_inner(&self, inner: InnerAddrs) {
+        *self.inner.write().unwrap() = Some(inner);
+    }
+
+    /// Accepts connections on `listener` and forwards them to the `inner` address picked by
+    /// `pick`, each on its own thread.
+    pub fn run(self: Arc<Self>, listener: TcpListener, pick: fn(&InnerAddrs) -> SocketAddr) {
+        for client in listener.incoming() {
+            let client = match client {
+                Ok(client) => client,
+                Err(err) => {
+                    warn!("Failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
+            let from = match client.peer_addr() {
+                Ok(addr) => addr.ip(),
+                Err(_) => continue,
+            };
+            if self.bans.read().unwrap().contains(&from) {
+                info!("Refused a connection from banned {}", from);
+                continue;
+            }
+            let gate = self.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = gate.forward(client, from, pick) {
+                    warn!("Failed to forward a connection from {}: {}", from, err);
+                }
+            });
+        }
+    }
+
+    fn forward(
+        &self,
+        client: TcpStream,
+        from: IpAddr,
+        pick: fn(&InnerAddrs) -> SocketAddr,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let upstream = loop {
+            // Read again on every attempt, quad-net may have started over on other ports.
+            let inner = *self.inner.read().unwrap();
+            let result = match inner {
+                Some(inner) => TcpStream::connect(pick(&inner)),
+                None => Err(io::ErrorKind::NotConnected.into()),
+            };
+            match result {
+                Ok(upstream) => break upstream,
+                Err(_) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.open
+            .lock()
+            .unwrap()
+            .insert(id, (from, client.try_clone()?));
+        let result = pipe(client, upstream);
+        self.open.lock().unwrap().remove(&id);
+        result
+    }
+}
+
+/// Copies bytes both ways until either side closes, then closes both.
+fn pipe(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let (mut client_read, mut upstream_write) = (client.try_clone()?, upstream.try_clone()?);
+    let to_upstream = std::thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = client_read.shutdown(Shutdown::Both);
+        let _ = upstream_write.shutdown(Shutdown::Both);
+    });
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    let _ = io::copy(&mut upstream_read, &mut client_write);
+    let _ = upstream_read.shutdown(Shutdown::Both);
+    let _ = client_write.shutdown(Shutdown::Both);
+    let _ = to_upstream.join();
+    Ok(())
+}
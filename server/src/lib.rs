@@ -1,138 +1,1215 @@
 use macroquad::prelude::*;
-use macroquad_tiled as tiled;
 
-use ::rand::seq::SliceRandom;
+use ::rand::{seq::SliceRandom, Rng};
+use gate::Gate;
+use metrics::Metrics;
 use nanoserde::DeBin;
-use std::cell::Cell;
+use quad_net::quad_socket::server::SocketHandle;
+use shared::map::MapInfo;
+use sim::{Input, Sim, TileMap};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+mod console;
+mod discovery;
+mod gate;
+mod metrics;
+
+const MAP_PATH: &str = "client/assets/map.json";
+const TCP_PORT: u16 = 8090;
+const WS_PORT: u16 = 8091;
+const JOIN_CODE_LENGTH: usize = 4;
+const COUNTDOWN: Duration = Duration::from_secs(3);
+const MAX_NAME_LENGTH: usize = 16;
+const MAX_PLAYERS: usize = 8;
+/// Matches end once one player is left, so they need at least two.
+const MIN_MATCH_PLAYERS: usize = 2;
+/// Connections that send nothing, not even a heartbeat, for this long are dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How far past the furthest input it has a handoff pauses the match. Players may be a batch of
+/// inputs and backroll's prediction window ahead of what they sent, and keep playing while the
+/// `Pause` reaches them.
+const HANDOFF_LEAD_FRAMES: u32 = 30;
 
 struct Player {
-    port: u16,
-    x: u16,
-    y: u8,
+    /// The address the client advertised for the match.
+    addr: SocketAddr,
+    /// `shared::Join::local`, telling apart players that share `addr`.
+    local: u8,
+    /// Assigned when the match starts.
+    spawn: (i32, i32),
+    /// Index into the current match's `shared::Start`, if the player is in it.
+    match_id: Option<u32>,
+    name: String,
+    color: (u8, u8, u8),
+    ready: bool,
+    /// Join order within the lobby, since slots get reused.
+    joined: u64,
+    /// Set by an admin; the player's connection drops them on its next tick.
+    kicked: bool,
+    /// How the player saw the current match end, once they reported it.
+    report: Option<shared::MatchReport>,
+    /// Stopped playing for a handoff.
+    paused: bool,
+}
+
+impl Player {
+    fn reported(&self) -> bool {
+        self.report.is_some()
+    }
+}
+
+/// What `Lobby::tick` did, for the metrics.
+enum Tick {
+    Started,
+    Finished { disputed: bool },
+}
+
+struct LobbySettings {
+    /// Fewest players the lobby will start a match with, at least `MIN_MATCH_PLAYERS`.
+    min_players: usize,
+    max_players: usize,
+    /// Start the countdown as soon as everyone is ready, without waiting for the host.
+    auto_start: bool,
+    tick_rate: u32,
+    score_limit: u32,
+}
+
+impl Default for LobbySettings {
+    fn default() -> Self {
+        Self {
+            min_players: MIN_MATCH_PLAYERS,
+            max_players: MAX_PLAYERS,
+            auto_start: true,
+            tick_rate: 60,
+            score_limit: 0,
+        }
+    }
 }
 
 #[derive(Default)]
 struct Lobby {
+    code: String,
+    settings: LobbySettings,
+    /// Set by whoever created the lobby.
+    password: Option<String>,
+    /// Hidden from `LobbyList`.
+    private: bool,
     players: Vec<Option<Player>>,
+    countdown_started: Option<Instant>,
     started: bool,
+    /// Roster and settings of the current match.
+    current_match: Option<shared::Start>,
+    /// Outcome of the last match.
+    results: Option<shared::MatchResults>,
+    /// Inputs of each player of the current match since it started or was handed off, as
+    /// `sim::Input` bits, for spectators and the next handoff.
+    inputs: Vec<Vec<u8>>,
+    /// The current match as of its start or last handoff. `inputs` continue from its frame.
+    replay: Option<Sim>,
+    /// Bumped whenever a match starts or resumes with a new roster, so each connection knows
+    /// when to send `Start`.
+    session: u64,
+    /// Players joined or left the match. It pauses at this frame and waits for every match
+    /// player to get there before it goes on with the new roster.
+    handoff_frame: Option<u32>,
+    joins: u64,
+    /// Bumped on every roster change so each connection knows when to resend `LobbyUpdate`.
+    revision: u64,
 }
 
 impl Lobby {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(code: String) -> Self {
+        Self {
+            code,
+            ..Default::default()
+        }
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &Player> {
+        self.players.iter().flatten()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players().next().is_none()
+    }
+
+    /// The first free slot, reusing the slots of players that left.
+    pub fn free_slot(&self) -> usize {
+        self.players
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.players.len())
+    }
+
+    pub fn add_player(&mut self, index: usize, mut player: Player) {
+        player.joined = self.joins;
+        self.joins += 1;
+        if index == self.players.len() {
+            self.players.push(Some(player));
+        } else {
+            self.players[index] = Some(player);
+        }
+        self.roster_changed();
+    }
+
+    /// The host is the longest-present player.
+    pub fn host(&self) -> Option<usize> {
+        self.players
+            .iter()
+            .enumerate()
+            .filter_map(|(index, player)| player.as_ref().map(|player| (index, player.joined)))
+            .min_by_key(|(_, joined)| *joined)
+            .map(|(index, _)| index)
+    }
+
+    pub fn can_start(&self) -> bool {
+        let mut count = 0;
+        for player in self.players() {
+            if !player.ready {
+                return false;
+            }
+            count += 1;
+        }
+        count >= self.settings.min_players
+    }
+
+    pub fn start_countdown(&mut self) {
+        if self.countdown_started.is_none() {
+            info!("Lobby {} counting down", self.code);
+            self.countdown_started = Some(Instant::now());
+        }
+    }
+
+    /// Called after a player joined, left or changed readiness: starts or cancels the countdown
+    /// and marks the roster for rebroadcast.
+    pub fn roster_changed(&mut self) {
+        self.revision += 1;
+        if self.started {
+            return;
+        }
+        if !self.can_start() {
+            if self.countdown_started.take().is_some() {
+                info!("Lobby {} countdown cancelled", self.code);
+            }
+        } else if self.settings.auto_start {
+            self.start_countdown();
+        }
+    }
+
+    pub fn seconds_left(&self, now: Instant) -> Option<u8> {
+        self.countdown_started.map(|countdown_started| {
+            let elapsed = now.saturating_duration_since(countdown_started);
+            let left = COUNTDOWN.saturating_sub(elapsed);
+            (left.as_millis() as f32 / 1000.0).ceil() as u8
+        })
+    }
+
+    pub fn start(&mut self, map: &MapInfo) {
+        info!("Starting game in lobby {}...", self.code);
+        let mut spawns = map.assign_spawns(self.players().count());
+        spawns.shuffle(&mut ::rand::thread_rng());
+        for (id, (player, (x, y))) in self.players.iter_mut().flatten().zip(spawns).enumerate() {
+            player.spawn = (x.floor() as i32, y.floor() as i32);
+            player.match_id = Some(id as u32);
+            player.report = None;
+        }
+        let settings = shared::MatchSettings {
+            seed: ::rand::random(),
+            map: map.name.clone(),
+            map_hash: map.hash,
+            tick_rate: self.settings.tick_rate,
+            rules: shared::Rules::default(),
+            weapons: vec![shared::Weapon::pistol()],
+            score_limit: self.settings.score_limit,
+        };
+        let spawns: Vec<(i32, i32)> = self.players().map(|player| player.spawn).collect();
+        self.replay = Some(
+            Sim::new(&settings, TileMap::from_info(map), &spawns)
+                .expect("The server should only start matches that can be played"),
+        );
+        self.current_match = Some(shared::Start {
+            players: self.start_players(),
+            settings,
+            resume: None,
+        });
+        self.inputs = vec![vec![]; self.players().count()];
+        self.session += 1;
+        self.handoff_frame = None;
+        self.countdown_started = None;
+        self.started = true;
+    }
+
+    fn start_players(&self) -> Vec<shared::StartPlayer> {
+        self.players()
+            .map(|player| shared::StartPlayer {
+                ip: player.addr.ip().to_string(),
+                port: player.addr.port(),
+                local: player.local,
+                spawn: player.spawn,
+                name: player.name.clone(),
+                color: player.color,
+            })
+            .collect()
+    }
+
+    /// The frame `inputs` start at.
+    fn inputs_from(&self) -> u32 {
+        self.replay.as_ref().map_or(0, |replay| replay.state.frame)
+    }
+
+    /// Pauses the match so that it goes on with everyone now in the lobby, unless it is ending
+    /// anyway. Players may be at different frames, so they all play up to a frame ahead of
+    /// every input sent so far.
+    pub fn request_handoff(&mut self) {
+        if !self.started
+            || self.handoff_frame.is_some()
+            || self.match_players().any(Player::reported)
+        {
+            return;
+        }
+        let played = self.inputs.iter().map(Vec::len).max().unwrap_or(0) as u32;
+        let frame = self.inputs_from() + played + HANDOFF_LEAD_FRAMES;
+        info!(
+            "Lobby {} pausing its match at frame {} for a new roster",
+            self.code, frame
+        );
+        self.handoff_frame = Some(frame);
+    }
+
+    /// Resumes the paused match with everyone now in the lobby. Replays the inputs since the
+    /// last start or handoff up to the handoff frame, and hands the result to the new roster as
+    /// a snapshot.
+    fn hand_off(&mut self, map: &MapInfo) {
+        let inputs_from = self.inputs_from();
+        let Self {
+            replay,
+            inputs,
+            players,
+            current_match,
+            handoff_frame,
+            ..
+        } = self;
+        let replay = replay.as_mut().unwrap();
+        let handoff_frame = handoff_frame.take().unwrap();
+        for frame in 0..handoff_frame.saturating_sub(inputs_from) as usize {
+            replay.advance(|id| {
+                // Everyone still here played up to the handoff frame and sent their inputs
+                // before pausing, so players who have none left the game. They stand still
+                // from their last batch on, which can be up to a batch before the frame the
+                // peers disconnected them on. The snapshot is what everyone goes on from.
+                inputs[id as usize]
+                    .get(frame)
+                    .map_or(Input::empty(), |bits| Input::from_bits_truncate(*bits))
+            });
+        }
+
+        let spawns = map.assign_spawns(players.iter().flatten().count());
+        let mut roster = vec![];
+        for (id, (player, (x, y))) in players.iter_mut().flatten().zip(spawns).enumerate() {
+            if player.match_id.is_none() {
+                player.spawn = (x.floor() as i32, y.floor() as i32);
+            }
+            roster.push(player.match_id);
+            player.match_id = Some(id as u32);
+            player.paused = false;
+        }
+        let spawns: Vec<(i32, i32)> = players
+            .iter()
+            .flatten()
+            .map(|player| player.spawn)
+            .collect();
+        replay.change_roster(&roster, &spawns);
+        info!(
+            "Lobby {} resumes its match at frame {} with {} players",
+            self.code,
+            replay.state.frame,
+            roster.len()
+        );
+        let resume = Some(replay.snapshot());
+        let settings = current_match.take().unwrap().settings;
+        self.current_match = Some(shared::Start {
+            players: self.start_players(),
+            settings,
+            resume,
+        });
+        self.inputs = vec![vec![]; roster.len()];
+        self.session += 1;
+    }
+
+    /// Appends a batch of a match player's inputs. Batches may overlap the inputs recorded so
+    /// far, but not leave a gap.
+    pub fn record_inputs(
+        &mut self,
+        id: u32,
+        first_frame: u32,
+        inputs: Vec<u8>,
+    ) -> Result<(), shared::Error> {
+        let inputs_from = self.inputs_from() as usize;
+        let recorded = self
+            .inputs
+            .get_mut(id as usize)
+            .ok_or(shared::Error::UnexpectedMessage)?;
+        let first_frame = first_frame as usize;
+        let next_frame = inputs_from + recorded.len();
+        if first_frame > next_frame {
+            return Err(shared::Error::BadMessage);
+        }
+        recorded.extend(inputs.into_iter().skip(next_frame - first_frame));
+        Ok(())
+    }
+
+    pub fn report(&mut self, index: usize, report: shared::MatchReport) {
+        if let Some(player) = &mut self.players[index] {
+            player.report = Some(report);
+        }
+    }
+
+    /// Ends the match and sends everyone back to the lobby, unready. Returns whether the
+    /// players' reports disagreed.
+    pub fn finish(&mut self) -> bool {
+        // Clients can disagree, e.g. after a desync or with a cheater among them, so go with
+        // the report most of them sent. Without a majority there is no telling who is right.
+        let mut votes: Vec<(&shared::MatchReport, usize)> = vec![];
+        let mut total = 0;
+        for report in self
+            .match_players()
+            .filter_map(|player| player.report.as_ref())
+        {
+            total += 1;
+            match votes.iter_mut().find(|(agreed, _)| *agreed == report) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((report, 1)),
+            }
+        }
+        let disputed = votes.len() > 1;
+        if disputed {
+            warn!(
+                "Players in lobby {} disagree on how the match ended, a desync or a cheat",
+                self.code
+            );
+            for player in self.match_players() {
+                warn!("{} reported {:?}", player.name, player.report);
+            }
+        }
+        let agreed = votes
+            .into_iter()
+            .find(|(_, count)| *count * 2 > total)
+            .map(|(report, _)| report.clone());
+        let players = self
+            .current_match
+            .as_ref()
+            .map_or(&[][..], |current_match| &current_match.players[..]);
+        let name = |id: usize| players.get(id).map(|player| player.name.clone());
+        let winner = agreed
+            .as_ref()
+            .and_then(|report| report.winner)
+            .and_then(|winner| name(winner as usize));
+        let scoreboard: Vec<(String, u32)> = agreed
+            .iter()
+            .flat_map(|report| report.kills.iter().enumerate())
+            .filter_map(|(id, kills)| Some((name(id)?, *kills)))
+            .collect();
+        info!(
+            "Match in lobby {} is over, winner: {:?}, scoreboard: {:?}",
+            self.code, winner, scoreboard
+        );
+        self.results = Some(shared::MatchResults {
+            winner,
+            scoreboard,
+            disputed,
+        });
+        self.started = false;
+        self.replay = None;
+        for player in self.players.iter_mut().flatten() {
+            player.ready = false;
+            player.match_id = None;
+        }
+        self.roster_changed();
+        disputed
+    }
+
+    /// Players taking part in the current match, unlike those who joined since its last
+    /// handoff.
+    pub fn match_players(&self) -> impl Iterator<Item = &Player> {
+        self.players().filter(|player| player.match_id.is_some())
+    }
+
+    /// Starts the match once the countdown runs out, hands it off once its players paused, and
+    /// ends it once every remaining player reported its end.
+    pub fn tick(&mut self, now: Instant, map: &MapInfo) -> Option<Tick> {
+        if self.started {
+            if self.match_players().all(Player::reported) {
+                let disputed = self.finish();
+                return Some(Tick::Finished { disputed });
+            }
+            // Players can see the match end before the pause reaches them. Their report
+            // stands, the snapshot ends the match for everyone else too.
+            if self.handoff_frame.is_some()
+                && self
+                    .match_players()
+                    .all(|player| player.paused || player.reported())
+            {
+                self.hand_off(map);
+            }
+        } else if self.seconds_left(now) == Some(0) {
+            self.start(map);
+            return Some(Tick::Started);
+        }
+        None
+    }
+
+    pub fn roster(&self) -> shared::LobbyUpdate {
+        let host = self.host();
+        shared::LobbyUpdate {
+            players: self
+                .players
+                .iter()
+                .enumerate()
+                .filter_map(|(index, possible_player)| {
+                    possible_player.as_ref().map(|player| shared::LobbyPlayer {
+                        name: player.name.clone(),
+                        color: player.color,
+                        ready: player.ready,
+                        host: host == Some(index),
+                    })
+                })
+                .collect(),
+        }
     }
 }
 
 #[derive(Default)]
 struct ClientState {
+    /// Set once the client sent an acceptable `Hello`.
+    greeted: bool,
     index: usize,
     started: Cell<bool>,
-    lobby: Arc<RwLock<Lobby>>,
+    /// `Lobby::session` of the last `Start` sent.
+    session_sent: Cell<u64>,
+    /// `Lobby::session` that the last `Pause` was sent for.
+    pause_sent: Cell<u64>,
+    countdown_sent: Cell<Option<u8>>,
+    last_seen: Cell<Option<Instant>>,
+    roster_sent: Cell<u64>,
+    lobby: Option<Arc<RwLock<Lobby>>>,
+    /// The lobby the client spectates, instead of being in `lobby`.
+    watching: Option<Arc<RwLock<Lobby>>>,
+    /// How many inputs of each match player were relayed to the spectator.
+    inputs_sent: RefCell<Vec<usize>>,
+    /// Set while the spectator is shown a match, from its `Start` until its results.
+    relaying: Cell<bool>,
+}
+
+impl ClientState {
+    /// Forgets the lobby the client was in or watched, and what it was sent about it, so that
+    /// it can join or watch another one like a new connection.
+    fn leave(&mut self) {
+        *self = Self {
+            greeted: self.greeted,
+            last_seen: Cell::new(self.last_seen.get()),
+            ..Self::default()
+        };
+    }
 }
 
+/// Open lobbies, by name or join code.
 #[derive(Default)]
-struct CurrentLobby {
-    lobby: Arc<RwLock<Lobby>>,
+struct Lobbies {
+    lobbies: HashMap<String, Arc<RwLock<Lobby>>>,
 }
 
-impl CurrentLobby {
+impl Lobbies {
     pub fn new() -> Self {
         Self::default()
     }
+
+    fn generate_code(&self) -> String {
+        let mut rng = ::rand::thread_rng();
+        loop {
+            let code: String = (0..JOIN_CODE_LENGTH)
+                .map(|_| rng.gen_range(b'A'..=b'Z') as char)
+                .collect();
+            if !self.lobbies.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    /// Returns the lobby, and whether it was just created.
+    pub fn find_or_create(&mut self, choice: shared::LobbyChoice) -> (Arc<RwLock<Lobby>>, bool) {
+        let code = match choice {
+            shared::LobbyChoice::New => self.generate_code(),
+            shared::LobbyChoice::Named(name) => name,
+        };
+        if let Some(lobby) = self.lobbies.get(&code) {
+            return (lobby.clone(), false);
+        }
+        info!("Created lobby {}", code);
+        let lobby = Arc::new(RwLock::new(Lobby::new(code.clone())));
+        self.lobbies.insert(code, lobby.clone());
+        (lobby, true)
+    }
+
+    pub fn remove(&mut self, lobby: &Arc<RwLock<Lobby>>) {
+        self.lobbies.retain(|_, other| !Arc::ptr_eq(lobby, other));
+    }
 }
 
-pub async fn lobby_main() {
-    let current_lobby = Arc::new(RwLock::new(CurrentLobby::new()));
-
-    let tileset = load_texture("client/assets/tileset.png").await.unwrap();
-    tileset.set_filter(FilterMode::Nearest);
-    let tiled_map_json = load_string("client/assets/map.json").await.unwrap();
-    let tiled_map = tiled::load_map(&tiled_map_json, &[("tileset.png", tileset)], &[]).unwrap();
-    let spawners = &tiled_map.layers["logic"].objects;
-    let spawner_positions: RwLock<Vec<Vec2>> = RwLock::new(
-        spawners
-            .iter()
-            .map(|spawner| vec2(spawner.world_x, spawner.world_y))
-            .collect(),
-    );
+/// Everything the connection threads share.
+struct Server {
+    /// Shown to clients browsing the local network.
+    name: String,
+    lobbies: RwLock<Lobbies>,
+    map: MapInfo,
+    gate: Arc<Gate>,
+    metrics: Metrics,
+}
 
-    {
-        let current_lobby = current_lobby.clone();
-        std::thread::spawn(move || {
-            quad_net::quad_socket::server::listen(
-                "0.0.0.0:8090",
-                "0.0.0.0:8091",
-                quad_net::quad_socket::server::Settings {
-                    on_message: {
-                        let current_lobby = current_lobby.clone();
-                        move |mut _out, state: &mut ClientState, msg| {
-                            let shared::Join(port) = DeBin::deserialize_bin(&msg).unwrap();
-                            let spawner_positions = spawner_positions.read().unwrap();
-                            let spawn_position =
-                                spawner_positions.choose(&mut ::rand::thread_rng()).unwrap();
-                            let player = Player {
-                                port,
-                                x: spawn_position.x as u16,
-                                y: spawn_position.y as u8,
-                            };
-                            let lobby = &current_lobby.read().unwrap().lobby;
-                            let mut lobby_write = lobby.write().unwrap();
-                            state.index = lobby_write.players.len();
-                            state.lobby = lobby.clone();
-                            lobby_write.players.push(Some(player));
-                            info!(
-                                "Player {} joined the lobby (from port: {})",
-                                state.index, port
-                            );
-                        }
-                    },
-                    on_timer: {
-                        move |out, state| {
-                            let lobby_read = state.lobby.read().unwrap();
-                            if lobby_read.started {
-                                info!("Player {} starting", state.index);
-                                let players = lobby_read
-                                    .players
-                                    .iter()
-                                    .filter_map(|possible_player| {
-                                        possible_player
-                                            .as_ref()
-                                            .map(|player| (player.port, (player.x, player.y)))
-                                    })
-                                    .collect();
-                                out.send_bin(&shared::Start(players)).unwrap();
-                                state.started.set(true);
-                                out.disconnect();
-                            }
-                        }
-                    },
-                    on_disconnect: {
-                        move |state| {
-                            if !state.started.get() {
-                                info!("Player {} left the lobby", state.index);
-                                state.lobby.write().unwrap().players[state.index] = None;
-                            }
-                        }
-                    },
-                    timer: Some(Duration::from_millis(1000 / 30)),
-                    _marker: std::marker::PhantomData,
-                },
+fn send(out: &mut SocketHandle, msg: &shared::ServerMsg) {
+    if out.send_bin(msg).is_err() {
+        warn!("Failed to send {:?}", msg);
+    }
+}
+
+impl Server {
+    fn on_message(&self, out: &mut SocketHandle, state: &mut ClientState, msg: Vec<u8>) {
+        state.last_seen.set(Some(Instant::now()));
+        let result = match DeBin::deserialize_bin(&msg) {
+            Ok(msg) => self.handle_message(out, state, msg),
+            Err(_) => Err(shared::Error::BadMessage),
+        };
+        if let Err(err) = result {
+            warn!("Player {}: {:?}", state.index, err);
+            if metrics::is_protocol_error(&err) {
+                self.metrics.protocol_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            let fatal = matches!(
+                err,
+                shared::Error::VersionMismatch(_)
+                    | shared::Error::BuildMismatch
+                    | shared::Error::MapMismatch
             );
-        });
+            send(out, &shared::ServerMsg::Error(err));
+            if fatal {
+                out.disconnect();
+            }
+        }
     }
 
-    loop {
-        if is_key_pressed(KeyCode::Enter) {
-            info!("Starting game...");
-            let lobby = &mut current_lobby.write().unwrap().lobby;
-            {
-                let mut lobby_write = lobby.write().unwrap();
-                lobby_write.started = true;
+    fn handle_message(
+        &self,
+        out: &mut SocketHandle,
+        state: &mut ClientState,
+        msg: shared::ClientMsg,
+    ) -> Result<(), shared::Error> {
+        match msg {
+            shared::ClientMsg::Hello(hello) => {
+                if state.greeted {
+                    return Err(shared::Error::UnexpectedMessage);
+                }
+                if hello.version != shared::PROTOCOL_VERSION {
+                    return Err(shared::Error::VersionMismatch(shared::PROTOCOL_VERSION));
+                }
+                if hello.build != shared::build_hash() {
+                    return Err(shared::Error::BuildMismatch);
+                }
+                if hello.map != self.map.hash {
+                    return Err(shared::Error::MapMismatch);
+                }
+                state.greeted = true;
+                send(out, &shared::ServerMsg::Welcome);
+                Ok(())
+            }
+            _ if !state.greeted => Err(shared::Error::UnexpectedMessage),
+            shared::ClientMsg::Join(join) => self.join(out, state, join),
+            shared::ClientMsg::Ready => self.set_ready(state, true),
+            shared::ClientMsg::Unready => self.set_ready(state, false),
+            shared::ClientMsg::StartRequest => self.request_start(state),
+            shared::ClientMsg::Heartbeat => Ok(()),
+            shared::ClientMsg::MatchOver(report) => self.report(state, report),
+            shared::ClientMsg::Spectate(spectate) => self.spectate(state, spectate),
+            shared::ClientMsg::Inputs(inputs) => self.record_inputs(state, inputs),
+            shared::ClientMsg::Paused => self.pause(state),
+            shared::ClientMsg::ListLobbies => {
+                send(out, &shared::ServerMsg::LobbyList(self.lobby_list()));
+                Ok(())
+            }
+            shared::ClientMsg::Leave => {
+                if let Some(lobby) = &state.lobby {
+                    self.remove_player(lobby, state.index);
+                } else if state.watching.is_none() {
+                    return Err(shared::Error::UnexpectedMessage);
+                }
+                state.leave();
+                Ok(())
+            }
+        }
+    }
+
+    fn join(
+        &self,
+        out: &mut SocketHandle,
+        state: &mut ClientState,
+        join: shared::Join,
+    ) -> Result<(), shared::Error> {
+        let shared::Join {
+            ip,
+            port,
+            local,
+            lobby,
+            name,
+            color,
+            password,
+            private,
+        } = join;
+        if state.lobby.is_some() || state.watching.is_some() {
+            return Err(shared::Error::UnexpectedMessage);
+        }
+        let ip: IpAddr = ip.parse().map_err(|_| shared::Error::BadMessage)?;
+        let addr = SocketAddr::new(ip, port);
+        let mut lobbies = self.lobbies.write().unwrap();
+        let (lobby, created) = lobbies.find_or_create(lobby);
+        let mut lobby_write = lobby.write().unwrap();
+        if lobby_write.started && lobby_write.match_players().any(Player::reported) {
+            return Err(shared::Error::MatchInProgress);
+        }
+        if created {
+            if !password.is_empty() {
+                lobby_write.password = Some(password);
+            }
+            lobby_write.private = private;
+        } else if let Some(lobby_password) = &lobby_write.password {
+            if *lobby_password != password {
+                return Err(shared::Error::WrongPassword);
             }
-            *lobby = Arc::new(RwLock::new(Lobby::new()));
         }
+        if lobby_write.players().count() >= self.max_players(&lobby_write) {
+            return Err(shared::Error::LobbyFull);
+        }
+        state.index = lobby_write.free_slot();
+        state.lobby = Some(lobby.clone());
+        let mut name: String = name.trim().chars().take(MAX_NAME_LENGTH).collect();
+        if name.is_empty() {
+            name = format!("player {}", state.index);
+        }
+        info!(
+            "Player {} ({}) joined lobby {} (from: {})",
+            state.index, name, lobby_write.code, addr
+        );
+        lobby_write.add_player(
+            state.index,
+            Player {
+                addr,
+                local,
+                spawn: (0, 0),
+                match_id: None,
+                name,
+                color,
+                ready: false,
+                joined: 0,
+                kicked: false,
+                report: None,
+                paused: false,
+            },
+        );
+        // Late joiners take part from the next handoff.
+        lobby_write.request_handoff();
+        self.metrics.joins.fetch_add(1, Ordering::Relaxed);
+        send(
+            out,
+            &shared::ServerMsg::Joined(shared::Joined {
+                code: lobby_write.code.clone(),
+            }),
+        );
+        Ok(())
+    }
+
+    /// Lobbies can be limited further by the map's spawn points.
+    fn max_players(&self, lobby: &Lobby) -> usize {
+        lobby.settings.max_players.min(self.map.max_players())
+    }
+
+    fn lobby_list(&self) -> Vec<shared::LobbyInfo> {
+        let mut list: Vec<shared::LobbyInfo> = self
+            .lobbies
+            .read()
+            .unwrap()
+            .lobbies
+            .values()
+            .filter_map(|lobby| {
+                let lobby = lobby.read().unwrap();
+                if lobby.private {
+                    return None;
+                }
+                Some(shared::LobbyInfo {
+                    name: lobby.code.clone(),
+                    map: self.map.name.clone(),
+                    players: lobby.players().count() as u32,
+                    max_players: self.max_players(&lobby) as u32,
+                    in_progress: lobby.started,
+                    password: lobby.password.is_some(),
+                })
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    fn set_ready(&self, state: &ClientState, ready: bool) -> Result<(), shared::Error> {
+        let lobby = state
+            .lobby
+            .as_ref()
+            .ok_or(shared::Error::UnexpectedMessage)?;
+        let mut lobby = lobby.write().unwrap();
+        if lobby.started {
+            return Err(shared::Error::UnexpectedMessage);
+        }
+        if let Some(player) = &mut lobby.players[state.index] {
+            player.ready = ready;
+        }
+        info!(
+            "Player {} is {} in lobby {}",
+            state.index,
+            if ready { "ready" } else { "not ready" },
+            lobby.code
+        );
+        lobby.roster_changed();
+        Ok(())
+    }
+
+    fn request_start(&self, state: &ClientState) -> Result<(), shared::Error> {
+        let lobby = state
+            .lobby
+            .as_ref()
+            .ok_or(shared::Error::UnexpectedMessage)?;
+        let mut lobby = lobby.write().unwrap();
+        if lobby.started {
+            return Err(shared::Error::UnexpectedMessage);
+        }
+        if lobby.host() != Some(state.index) {
+            warn!(
+                "Player {} requested a start but is not the host of lobby {}",
+                state.index, lobby.code
+            );
+        } else if !lobby.can_start() {
+            warn!(
+                "Player {} requested a start but lobby {} is not ready",
+                state.index, lobby.code
+            );
+        } else {
+            lobby.start_countdown();
+        }
+        Ok(())
+    }
+
+    fn report(
+        &self,
+        state: &ClientState,
+        report: shared::MatchReport,
+    ) -> Result<(), shared::Error> {
+        let lobby = state
+            .lobby
+            .as_ref()
+            .ok_or(shared::Error::UnexpectedMessage)?;
+        let mut lobby = lobby.write().unwrap();
+        if !lobby.started {
+            return Err(shared::Error::UnexpectedMessage);
+        }
+        info!(
+            "Player {} reports the match in lobby {} is over at frame {}, winner: {:?}",
+            state.index, lobby.code, report.frame, report.winner
+        );
+        lobby.report(state.index, report);
+        Ok(())
+    }
+
+    fn spectate(
+        &self,
+        state: &mut ClientState,
+        spectate: shared::Spectate,
+    ) -> Result<(), shared::Error> {
+        if state.lobby.is_some() || state.watching.is_some() {
+            return Err(shared::Error::UnexpectedMessage);
+        }
+        let lobby = self
+            .lobbies
+            .read()
+            .unwrap()
+            .lobbies
+            .get(&spectate.lobby)
+            .cloned()
+            .ok_or(shared::Error::NoSuchLobby)?;
+        if let Some(password) = &lobby.read().unwrap().password {
+            if *password != spectate.password {
+                return Err(shared::Error::WrongPassword);
+            }
+        }
+        info!("A spectator is watching lobby {}", spectate.lobby);
+        state.watching = Some(lobby);
+        Ok(())
+    }
+
+    fn record_inputs(
+        &self,
+        state: &ClientState,
+        inputs: shared::Inputs,
+    ) -> Result<(), shared::Error> {
+        let lobby = state
+            .lobby
+            .as_ref()
+            .ok_or(shared::Error::UnexpectedMessage)?;
+        let mut lobby = lobby.write().unwrap();
+        let id = match &lobby.players[state.index] {
+            Some(Player {
+                match_id: Some(id), ..
+            }) if lobby.started => *id,
+            // The last batch can arrive after the match ended, nobody needs it anymore.
+            _ => return Ok(()),
+        };
+        lobby.record_inputs(id, inputs.first_frame, inputs.inputs)
+    }
+
+    fn pause(&self, state: &ClientState) -> Result<(), shared::Error> {
+        let lobby = state
+            .lobby
+            .as_ref()
+            .ok_or(shared::Error::UnexpectedMessage)?;
+        let mut lobby = lobby.write().unwrap();
+        if lobby.handoff_frame.is_none() {
+            return Err(shared::Error::UnexpectedMessage);
+        }
+        info!("Player {} paused in lobby {}", state.index, lobby.code);
+        if let Some(player) = &mut lobby.players[state.index] {
+            player.paused = true;
+        }
+        Ok(())
+    }
+
+    /// Sends a spectator the lobby's matches: `Start` once, then new inputs every tick, and
+    /// the results when the match ends.
+    fn relay(&self, out: &mut SocketHandle, state: &ClientState, lobby: &Arc<RwLock<Lobby>>) {
+        let lobby = lobby.read().unwrap();
+        if lobby.is_empty() {
+            info!("Lobby {} closed, dropping its spectator", lobby.code);
+            send(out, &shared::ServerMsg::Error(shared::Error::NoSuchLobby));
+            out.disconnect();
+            return;
+        }
+        if lobby.started
+            && lobby.handoff_frame.is_none()
+            && state.session_sent.get() != lobby.session
+        {
+            if let Some(start) = &lobby.current_match {
+                send(out, &shared::ServerMsg::Start(start.clone()));
+            }
+            state.relaying.set(true);
+            state.session_sent.set(lobby.session);
+            state.inputs_sent.replace(vec![0; lobby.inputs.len()]);
+        }
+        if !state.relaying.get() {
+            return;
+        }
+        // The last inputs can arrive just before the match ends, they go out ahead of the
+        // results.
+        let inputs_from = lobby.inputs_from();
+        let played = lobby.inputs.iter().map(Vec::len).max().unwrap_or(0);
+        let mut inputs_sent = state.inputs_sent.borrow_mut();
+        for (id, (inputs, sent)) in lobby.inputs.iter().zip(inputs_sent.iter_mut()).enumerate() {
+            // Players who left send nothing more and stand still, see `sim::Input`. Their
+            // empty inputs keep the spectator from waiting for them.
+            let left = lobby.started
+                && !lobby
+                    .match_players()
+                    .any(|player| player.match_id == Some(id as u32));
+            let available = if left { played } else { inputs.len() };
+            if available > *sent {
+                send(
+                    out,
+                    &shared::ServerMsg::Inputs(shared::Inputs {
+                        player: id as u32,
+                        first_frame: inputs_from + *sent as u32,
+                        inputs: (*sent..available)
+                            .map(|frame| inputs.get(frame).copied().unwrap_or_default())
+                            .collect(),
+                    }),
+                );
+                *sent = available;
+            }
+        }
+        if !lobby.started {
+            if let Some(results) = &lobby.results {
+                send(out, &shared::ServerMsg::MatchResults(results.clone()));
+            }
+            state.relaying.set(false);
+        }
+    }
+
+    fn on_timer(&self, out: &mut SocketHandle, state: &ClientState) {
+        let now = Instant::now();
+        match state.last_seen.get() {
+            None => state.last_seen.set(Some(now)),
+            Some(last_seen) if now.saturating_duration_since(last_seen) > IDLE_TIMEOUT => {
+                warn!("Player {} timed out", state.index);
+                send(out, &shared::ServerMsg::Error(shared::Error::TimedOut));
+                out.disconnect();
+                return;
+            }
+            Some(_) => {}
+        }
+
+        if let Some(lobby) = &state.watching {
+            self.relay(out, state, lobby);
+            return;
+        }
+        let lobby = match &state.lobby {
+            Some(lobby) => lobby,
+            None => return,
+        };
+        match lobby.write().unwrap().tick(now, &self.map) {
+            Some(Tick::Started) => self.lobby_started(),
+            Some(Tick::Finished { disputed }) => {
+                self.metrics
+                    .matches_finished
+                    .fetch_add(1, Ordering::Relaxed);
+                if disputed {
+                    self.metrics
+                        .disputed_matches
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            None => {}
+        }
+        let lobby_read = lobby.read().unwrap();
+        if let Some(Player { kicked: true, .. }) = lobby_read.players[state.index] {
+            info!("Player {} was kicked", state.index);
+            send(out, &shared::ServerMsg::Error(shared::Error::Kicked));
+            out.disconnect();
+            return;
+        }
+        // Also during matches, for late joiners waiting for the next handoff.
+        if lobby_read.revision != state.roster_sent.get() {
+            send(out, &shared::ServerMsg::LobbyUpdate(lobby_read.roster()));
+            state.roster_sent.set(lobby_read.revision);
+        }
+        if lobby_read.started {
+            let in_match = matches!(
+                lobby_read.players[state.index],
+                Some(Player {
+                    match_id: Some(_),
+                    ..
+                })
+            );
+            if !in_match {
+                return;
+            }
+            if let Some(frame) = lobby_read.handoff_frame {
+                if state.pause_sent.get() != lobby_read.session {
+                    info!("Player {} pausing at frame {}", state.index, frame);
+                    send(out, &shared::ServerMsg::Pause(frame));
+                    state.pause_sent.set(lobby_read.session);
+                }
+            } else if state.session_sent.get() != lobby_read.session {
+                info!("Player {} starting", state.index);
+                if let Some(start) = &lobby_read.current_match {
+                    send(out, &shared::ServerMsg::Start(start.clone()));
+                }
+                state.started.set(true);
+                state.session_sent.set(lobby_read.session);
+                state.countdown_sent.set(None);
+            }
+        } else {
+            if state.started.get() {
+                if let Some(results) = &lobby_read.results {
+                    send(out, &shared::ServerMsg::MatchResults(results.clone()));
+                }
+                state.started.set(false);
+            }
+            let seconds_left = lobby_read.seconds_left(now);
+            if seconds_left != state.countdown_sent.get() {
+                let msg = match seconds_left {
+                    Some(seconds_left) => shared::ServerMsg::Countdown(seconds_left),
+                    None => shared::ServerMsg::CountdownCancelled,
+                };
+                send(out, &msg);
+                state.countdown_sent.set(seconds_left);
+            }
+        }
+    }
+
+    fn on_disconnect(&self, state: &ClientState) {
+        self.metrics.disconnects.fetch_add(1, Ordering::Relaxed);
+        let lobby = match &state.lobby {
+            Some(lobby) => lobby,
+            None => return,
+        };
+        self.remove_player(lobby, state.index);
+    }
+
+    /// Starts the lobby's match right away, unless it is already playing one or a match could
+    /// never end with its players.
+    fn start_lobby(&self, lobby: &Arc<RwLock<Lobby>>) -> Result<(), String> {
+        {
+            let mut lobby = lobby.write().unwrap();
+            if lobby.started {
+                return Err("is already playing".to_string());
+            }
+            if lobby.players().count() < MIN_MATCH_PLAYERS {
+                return Err(format!("needs at least {} players", MIN_MATCH_PLAYERS));
+            }
+            lobby.start(&self.map);
+        }
+        self.lobby_started();
+        Ok(())
+    }
+
+    fn lobby_started(&self) {
+        self.metrics.matches_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Frees the player's slot for reuse, and removes the lobby once nobody is left in it.
+    fn remove_player(&self, lobby: &Arc<RwLock<Lobby>>, index: usize) {
+        let mut lobbies = self.lobbies.write().unwrap();
+        let mut lobby_write = lobby.write().unwrap();
+        info!("Player {} left lobby {}", index, lobby_write.code);
+        let in_match = matches!(
+            lobby_write.players[index],
+            Some(Player {
+                match_id: Some(_),
+                ..
+            })
+        );
+        lobby_write.players[index] = None;
+        lobby_write.roster_changed();
+        if in_match {
+            lobby_write.request_handoff();
+        }
+        if lobby_write.is_empty() {
+            info!("Lobby {} is empty, removing it", lobby_write.code);
+            lobbies.remove(lobby);
+        }
+    }
+}
+
+/// Serves lobbies on the given ports from background threads. Fails if a port is taken.
+fn spawn_listener(server: Arc<Server>, tcp_port: u16, ws_port: u16) -> Result<(), String> {
+    // quad-net only listens on the loopback interface, everyone else goes through the gate.
+    let bind = |port| {
+        TcpListener::bind(("0.0.0.0", port))
+            .map_err(|err| format!("Can not listen on port {}: {}", port, err))
+    };
+    let (tcp, ws) = (bind(tcp_port)?, bind(ws_port)?);
+    let gate = server.gate.clone();
+    std::thread::spawn(move || gate.run(tcp, |inner| inner.tcp));
+    let gate = server.gate.clone();
+    std::thread::spawn(move || gate.run(ws, |inner| inner.ws));
+
+    std::thread::spawn(move || loop {
+        let inner = match gate::InnerAddrs::find() {
+            Ok(inner) => inner,
+            Err(err) => {
+                error!("Ran out of ports on the loopback interface: {}", err);
+                return;
+            }
+        };
+        server.gate.set_inner(inner);
+        let settings = quad_net::quad_socket::server::Settings {
+            on_message: {
+                let server = server.clone();
+                move |out, state: &mut ClientState, msg| server.on_message(out, state, msg)
+            },
+            on_timer: {
+                let server = server.clone();
+                move |out, state| server.on_timer(out, state)
+            },
+            on_disconnect: {
+                let server = server.clone();
+                move |state| server.on_disconnect(state)
+            },
+            timer: Some(Duration::from_millis(1000 / 30)),
+            _marker: std::marker::PhantomData,
+        };
+        // quad-net serves forever unless it could not listen, which it panics about.
+        let _ = std::thread::spawn(move || {
+            quad_net::quad_socket::server::listen(inner.tcp, inner.ws, settings)
+        })
+        .join();
+        warn!(
+            "Could not listen on {} and {}, trying other ports",
+            inner.tcp, inner.ws
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    });
+    Ok(())
+}
+
+fn new_server(name: String, map_name: &str, map_json: &str) -> Result<Arc<Server>, String> {
+    Ok(Arc::new(Server {
+        name,
+        lobbies: RwLock::new(Lobbies::new()),
+        map: MapInfo::parse(map_name, map_json)?,
+        gate: Arc::new(Gate::default()),
+        metrics: Metrics::new(),
+    }))
+}
+
+/// Serves lobbies for `map_json` without a window, admin console or LAN discovery, so that
+/// tests can run a server next to their clients. Metrics are only served with a
+/// `metrics_port`. Fails if `map_json` is not a valid map.
+pub fn spawn_headless(
+    map_json: &str,
+    tcp_port: u16,
+    ws_port: u16,
+    metrics_port: Option<u16>,
+) -> Result<(), String> {
+    let server = new_server("Test server".to_string(), "map", map_json)?;
+    if let Some(metrics_port) = metrics_port {
+        let server = server.clone();
+        let addr = format!("127.0.0.1:{}", metrics_port);
+        std::thread::spawn(move || metrics::serve(&server, &addr));
+    }
+    spawn_listener(server, tcp_port, ws_port)
+}
+
+pub async fn lobby_main() {
+    let name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "Fish server".to_string());
+    let map_json = load_string(MAP_PATH).await.unwrap();
+    let map_name = shared::map::name_from_path(MAP_PATH);
+    let server = match new_server(name, &map_name, &map_json) {
+        Ok(server) => server,
+        Err(err) => {
+            error!("Can not serve the map: {}", err);
+            return;
+        }
+    };
+
+    {
+        let server = server.clone();
+        std::thread::spawn(move || metrics::serve(&server, "127.0.0.1:8092"));
+    }
+
+    {
+        let server = server.clone();
+        std::thread::spawn(move || console::run(&server));
+    }
+
+    {
+        let server = server.clone();
+        std::thread::spawn(move || discovery::run(&server));
+    }
+
+    if let Err(err) = spawn_listener(server.clone(), TCP_PORT, WS_PORT) {
+        error!("{}", err);
+        return;
+    }
+
+    loop {
         next_frame().await;
     }
 }
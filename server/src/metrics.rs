@@ -0,0 +1,234 @@
+//! Local HTTP endpoint with the server's status: `/status` as JSON and `/metrics` in the
+//! Prometheus text format.
+
+use super::Server;
+
+use macroquad::prelude::*;
+use nanoserde::SerJson;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long a client may take to send its request or read the answer. Requests are served one
+/// at a time, so a stalled client would otherwise block every other one.
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Metrics {
+    started: Instant,
+    pub matches_started: AtomicU64,
+    pub matches_finished: AtomicU64,
+    /// Finished matches whose players reported different results.
+    pub disputed_matches: AtomicU64,
+    pub joins: AtomicU64,
+    pub disconnects: AtomicU64,
+    /// Errors that `is_protocol_error`, not refusals like a wrong password.
+    pub protocol_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            matches_started: AtomicU64::new(0),
+            matches_finished: AtomicU64::new(0),
+            disputed_matches: AtomicU64::new(0),
+            joins: AtomicU64::new(0),
+            disconnects: AtomicU64::new(0),
+            protocol_errors: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Whether the client broke the protocol or speaks another version of it.
+pub fn is_protocol_error(err: &shared::Error) -> bool {
+    matches!(
+        err,
+        shared::Error::BadMessage
+            | shared::Error::UnexpectedMessage
+            | shared::Error::VersionMismatch(_)
+            | shared::Error::BuildMismatch
+    )
+}
+
+#[derive(SerJson)]
+struct LobbyStatus {
+    code: String,
+    players: usize,
+    max_players: usize,
+    counting_down: bool,
+    playing: bool,
+}
+
+#[derive(SerJson)]
+struct Status {
+    uptime_seconds: u64,
+    lobbies: Vec<LobbyStatus>,
+    matches_started: u64,
+    matches_finished: u64,
+    disputed_matches: u64,
+    joins: u64,
+    disconnects: u64,
+    protocol_errors: u64,
+}
+
+fn status(server: &Server) -> Status {
+    let metrics = &server.metrics;
+    let mut lobbies: Vec<LobbyStatus> = server
+        .lobbies
+        .read()
+        .unwrap()
+        .lobbies
+        .values()
+        .map(|lobby| {
+            let lobby = lobby.read().unwrap();
+            LobbyStatus {
+                code: lobby.code.clone(),
+                players: lobby.players().count(),
+                max_players: server.max_players(&lobby),
+                counting_down: lobby.countdown_started.is_some(),
+                playing: lobby.started,
+            }
+        })
+        .collect();
+    lobbies.sort_by(|a, b| a.code.cmp(&b.code));
+    Status {
+        uptime_seconds: metrics.started.elapsed().as_secs(),
+        lobbies,
+        matches_started: metrics.matches_started.load(Ordering::Relaxed),
+        matches_finished: metrics.matches_finished.load(Ordering::Relaxed),
+        disputed_matches: metrics.disputed_matches.load(Ordering::Relaxed),
+        joins: metrics.joins.load(Ordering::Relaxed),
+        disconnects: metrics.disconnects.load(Ordering::Relaxed),
+        protocol_errors: metrics.protocol_errors.load(Ordering::Relaxed),
+    }
+}
+
+fn prometheus(status: &Status) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        out += &format!("# HELP fishgame_{} {}\n", name, help);
+        out += &format!("# TYPE fishgame_{} {}\n", name, kind);
+        for (labels, value) in samples {
+            out += &format!("fishgame_{}{} {}\n", name, labels, value);
+        }
+    };
+    metric(
+        "uptime_seconds",
+        "gauge",
+        "Seconds since the server started.",
+        &[(String::new(), status.uptime_seconds)],
+    );
+    metric(
+        "lobbies",
+        "gauge",
+        "Open lobbies, including those playing a match.",
+        &[(String::new(), status.lobbies.len() as u64)],
+    );
+    metric(
+        "lobby_players",
+        "gauge",
+        "Players in each lobby.",
+        &status
+            .lobbies
+            .iter()
+            .map(|lobby| {
+                (
+                    format!("{{lobby=\"{}\"}}", escape_label(&lobby.code)),
+                    lobby.players as u64,
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "matches_started_total",
+        "counter",
+        "Matches started.",
+        &[(String::new(), status.matches_started)],
+    );
+    metric(
+        "matches_finished_total",
+        "counter",
+        "Matches every player reported the end of.",
+        &[(String::new(), status.matches_finished)],
+    );
+    metric(
+        "disputed_matches_total",
+        "counter",
+        "Finished matches whose players reported different results, from a desync or a cheat.",
+        &[(String::new(), status.disputed_matches)],
+    );
+    metric(
+        "joins_total",
+        "counter",
+        "Players that joined a lobby.",
+        &[(String::new(), status.joins)],
+    );
+    metric(
+        "disconnects_total",
+        "counter",
+        "Closed lobby connections.",
+        &[(String::new(), status.disconnects)],
+    );
+    metric(
+        "protocol_errors_total",
+        "counter",
+        "Messages that could not be decoded or were not valid at that point, and clients of \
+         another protocol version.",
+        &[(String::new(), status.protocol_errors)],
+    );
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn respond(server: &Server, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status_line, content_type, body) = match path {
+        "/status" => (
+            "200 OK",
+            "application/json",
+            SerJson::serialize_json(&status(server)),
+        ),
+        "/metrics" => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            prometheus(&status(server)),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Serves requests one at a time; the endpoint is only meant for a local dashboard or scraper.
+pub fn serve(server: &Server, addr: &str) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("Could not serve metrics on {}: {}", addr, err);
+            return;
+        }
+    };
+    for stream in listener.incoming().flatten() {
+        if let Err(err) = respond(server, stream) {
+            warn!("Failed to answer a metrics request: {}", err);
+        }
+    }
+}
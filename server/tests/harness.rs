@@ -0,0 +1,1044 @@
+//! Plays whole matches in one process: a headless lobby server and clients that join it the way
+//! the game does, then play scripted inputs against each other over UDP, optionally through
+//! `netsim`. Every frame's `State::checksum` has to agree between all peers, and with a
+//! spectator replaying the inputs the server relays. Matches that players join midway have to
+//! resume from the frame the server paused them at. Bots fight duels to the end, to check the
+//! reports of how a match ended. Players sharing a machine play in one session.
+
+use backroll_transport_udp::{UdpConnectionConfig, UdpManager};
+use bevy_tasks::TaskPool;
+use bot::{Bot, Difficulty};
+use netplay::{Seat, Session};
+use quad_net::quad_socket::client::QuadSocket;
+use shared::map::MapInfo;
+use sim::{Input, Sim, TileMap};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Frames whose checksums are compared.
+const FRAMES: u32 = 3000;
+/// Frame at which a player joins a match in progress.
+const HANDOFF_FRAME: u32 = 600;
+/// How far one peer runs ahead of the other when the joiner arrives, within backroll's
+/// prediction window.
+const AHEAD_FRAMES: u32 = 5;
+/// Frames played past `FRAMES`, so that the inputs of every compared frame are confirmed.
+const SETTLE_FRAMES: u32 = 30;
+/// How long a test may wait for the server or the other peers before it fails.
+const TIMEOUT: Duration = Duration::from_secs(60);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const LOBBY: &str = "harness";
+
+/// Players run back and forth and jump at different rhythms. Nobody shoots, so the match lasts
+/// as long as the test wants.
+fn script(frame: u32, player: u32) -> Input {
+    let mut input = Input::empty();
+    match frame / (30 + player * 11) % 3 {
+        0 => input |= Input::RIGHT,
+        1 => input |= Input::LEFT,
+        _ => {}
+    }
+    if (frame + player * 5) % 40 < 8 {
+        input |= Input::JUMP;
+    }
+    input
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Starts a headless lobby server with the client's map, serving metrics on `metrics_port` if
+/// given. Returns the map and the address to connect to.
+fn spawn_server_with(metrics_port: Option<u16>) -> (MapInfo, SocketAddr) {
+    let map_json = std::fs::read_to_string("../client/assets/map.json").unwrap();
+    let map = MapInfo::parse("map", &map_json).unwrap();
+    let tcp_port = free_port();
+    server::spawn_headless(&map_json, tcp_port, free_port(), metrics_port).unwrap();
+    (map, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), tcp_port))
+}
+
+fn spawn_server() -> (MapInfo, SocketAddr) {
+    spawn_server_with(None)
+}
+
+fn spawns(start: &shared::Start) -> Vec<(i32, i32)> {
+    start.players.iter().map(|player| player.spawn).collect()
+}
+
+/// A client's connection to the lobby server and the UDP socket it plays over.
+struct Client {
+    socket: QuadSocket,
+    local_addr: SocketAddr,
+    connection_manager: UdpManager,
+    /// Which of the players at `local_addr` this is, see `shared::Join::local`.
+    local: u8,
+    last_heartbeat: Instant,
+}
+
+impl Client {
+    fn connect(server_addr: SocketAddr, map_hash: u64, task_pool: &TaskPool) -> Self {
+        let deadline = Instant::now() + TIMEOUT;
+        // The server thread may not be listening yet.
+        let mut socket = loop {
+            match QuadSocket::connect(server_addr) {
+                Ok(socket) => break socket,
+                Err(_) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => panic!("Could not connect to the lobby server: {:?}", err),
+            }
+        };
+        socket.send_bin(&shared::ClientMsg::Hello(shared::Hello {
+            version: shared::PROTOCOL_VERSION,
+            build: shared::build_hash(),
+            map: map_hash,
+        }));
+
+        let local_port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), local_port);
+        let connection_manager = UdpManager::bind(task_pool.clone(), local_addr).unwrap();
+
+        let mut client = Self {
+            socket,
+            local_addr,
+            connection_manager,
+            local: 0,
+            last_heartbeat: Instant::now(),
+        };
+        client.wait_for("Welcome", |msg| match msg {
+            shared::ServerMsg::Welcome => Some(()),
+            _ => None,
+        });
+        client
+    }
+
+    fn send(&mut self, msg: &shared::ClientMsg) {
+        self.socket.send_bin(msg);
+    }
+
+    /// Joins as another player at `host`'s machine, whose peer plays us, see `Peer::guests`.
+    fn share_machine(&mut self, host: &Client, local: u8) {
+        self.local_addr = host.local_addr;
+        self.local = local;
+    }
+
+    /// Our `sim::PlayerState::id` in `start`.
+    fn player_id(&self, start: &shared::Start) -> u32 {
+        start
+            .players
+            .iter()
+            .position(|player| {
+                SocketAddr::new(player.ip.parse().unwrap(), player.port) == self.local_addr
+                    && player.local == self.local
+            })
+            .expect("We should be in the match") as u32
+    }
+
+    /// Joins the harness lobby as `Peer {index}`.
+    fn join(&mut self, index: usize) {
+        self.join_lobby(index, LOBBY);
+    }
+
+    fn join_lobby(&mut self, index: usize, lobby: &str) {
+        self.send(&shared::ClientMsg::Join(shared::Join {
+            ip: self.local_addr.ip().to_string(),
+            port: self.local_addr.port(),
+            local: self.local,
+            lobby: shared::LobbyChoice::Named(lobby.to_string()),
+            name: format!("Peer {}", index),
+            color: (255, 161, 0),
+            password: String::new(),
+            private: false,
+        }));
+        let code = self.wait_for("Joined", |msg| match msg {
+            shared::ServerMsg::Joined(shared::Joined { code }) => Some(code),
+            _ => None,
+        });
+        assert_eq!(code, lobby);
+    }
+
+    fn keep_alive(&mut self) {
+        if self.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            self.send(&shared::ClientMsg::Heartbeat);
+            self.last_heartbeat = Instant::now();
+        }
+    }
+
+    /// Waits until `accept` takes a message from the server, passing it every message that
+    /// arrives in the meantime. Fails on errors.
+    fn wait_for<T>(
+        &mut self,
+        what: &str,
+        mut accept: impl FnMut(shared::ServerMsg) -> Option<T>,
+    ) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            self.keep_alive();
+            while let Some(data) = self.socket.try_recv() {
+                let msg = nanoserde::DeBin::deserialize_bin(&data)
+                    .expect("The server sent a malformed message");
+                if let shared::ServerMsg::Error(err) = msg {
+                    panic!("Waiting for {}, the server replied {:?}", what, err);
+                }
+                if let Some(value) = accept(msg) {
+                    return value;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("Timed out waiting for {}", what);
+    }
+
+    /// Waits until the server replies `expected`.
+    fn wait_for_error(&mut self, expected: shared::Error) {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            while let Some(data) = self.socket.try_recv() {
+                let msg = nanoserde::DeBin::deserialize_bin(&data)
+                    .expect("The server sent a malformed message");
+                if let shared::ServerMsg::Error(err) = msg {
+                    assert_eq!(err, expected);
+                    return;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("Timed out waiting for {:?}", expected);
+    }
+}
+
+/// One client's side of a match, playing `script` or, once given one, a bot.
+struct Peer {
+    session: Session,
+    sim: Sim,
+    bot: Option<Bot>,
+    /// The lobby connections of the other players at our machine, with their ids.
+    guests: Vec<(u32, Client)>,
+    /// `State::checksum` after each frame, overwritten when a rollback simulates it again.
+    checksums: HashMap<u32, u64>,
+}
+
+impl Peer {
+    fn new(
+        client: &Client,
+        start: &shared::Start,
+        map: TileMap,
+        conditions: Option<&netsim::Conditions>,
+        task_pool: &TaskPool,
+    ) -> Self {
+        let seats = start
+            .players
+            .iter()
+            .map(|player| {
+                let addr = SocketAddr::new(player.ip.parse().unwrap(), player.port);
+                if addr == client.local_addr {
+                    Seat::Local
+                } else {
+                    Seat::Remote(addr)
+                }
+            })
+            .collect();
+        let mut sim = Sim::new(&start.settings, map, &spawns(start)).unwrap();
+        if let Some(snapshot) = &start.resume {
+            sim.restore(snapshot).unwrap();
+        }
+        let session = Session::new(task_pool, seats, &sim, |id, addr| {
+            let remote_peer = client
+                .connection_manager
+                .connect(UdpConnectionConfig::unbounded(addr));
+            match conditions {
+                Some(conditions) => netsim::wrap(
+                    task_pool,
+                    remote_peer,
+                    conditions.clone(),
+                    start.settings.seed ^ id as u64,
+                ),
+                None => remote_peer,
+            }
+        });
+        assert!(
+            session.local_players().contains(&client.player_id(start)),
+            "We should be in the match"
+        );
+        Self {
+            session,
+            sim,
+            bot: None,
+            guests: vec![],
+            checksums: HashMap::new(),
+        }
+    }
+
+    /// Plays `guest`, who shares our machine, too.
+    fn seat_guest(&mut self, start: &shared::Start, guest: Client) {
+        let id = guest.player_id(start);
+        assert!(self.session.local_players().contains(&id));
+        self.guests.push((id, guest));
+    }
+
+    /// Sends the server the inputs recorded so far, each over the lobby connection of its
+    /// player: `client` for ours, `guests` for theirs.
+    fn send_recorded(&mut self, client: &mut Client, flush: bool) {
+        for inputs in self.session.take_recorded(flush) {
+            let player = inputs.player;
+            let msg = shared::ClientMsg::Inputs(inputs);
+            match self.guests.iter_mut().find(|(id, _)| *id == player) {
+                Some((_, guest)) => guest.send(&msg),
+                None => client.send(&msg),
+            }
+        }
+        client.keep_alive();
+        for (_, guest) in &mut self.guests {
+            guest.keep_alive();
+        }
+    }
+
+    /// Hands our player to a bot, seeded like the bots of the bot binary.
+    fn fight(&mut self, start: &shared::Start) {
+        let id = self.session.local_players()[0];
+        self.bot = Some(Bot::new(
+            id,
+            Difficulty::Hard,
+            start.settings.seed ^ id as u64,
+        ));
+    }
+
+    /// Plays up to frame `last_frame`, then only keeps the session going.
+    fn update(&mut self, last_frame: u32) {
+        if self.sim.state.frame < last_frame {
+            let bot = &mut self.bot;
+            self.session
+                .update(&mut self.sim, |id, sim| match bot {
+                    Some(bot) => bot.input(sim),
+                    None => script(sim.state.frame, id),
+                })
+                .expect("Error in adding local input");
+        } else {
+            self.session.poll(&mut self.sim);
+        }
+        for report in self.session.history() {
+            self.checksums.insert(report.frame, report.state_hash);
+        }
+    }
+}
+
+/// Waits for every client's `Start` of the same match.
+fn wait_for_starts(clients: &mut [Client]) -> Vec<shared::Start> {
+    let starts: Vec<shared::Start> = clients
+        .iter_mut()
+        .map(|client| {
+            client.wait_for("Start", |msg| match msg {
+                shared::ServerMsg::Start(start) => Some(start),
+                _ => None,
+            })
+        })
+        .collect();
+    assert!(
+        starts.iter().all(|start| *start == starts[0]),
+        "Every player should get the same Start"
+    );
+    starts
+}
+
+/// Plays until every peer reached its entry of `last_frames`, sending their inputs to the
+/// server on the way. `idle` clients are only kept alive.
+fn play_until(
+    clients: &mut [Client],
+    peers: &mut [Peer],
+    idle: &mut [Client],
+    last_frames: &[u32],
+) {
+    let deadline = Instant::now() + TIMEOUT;
+    let reached = |peers: &[Peer]| {
+        peers
+            .iter()
+            .zip(last_frames)
+            .all(|(peer, last_frame)| peer.sim.state.frame >= *last_frame)
+    };
+    while !reached(peers) {
+        assert!(
+            Instant::now() < deadline,
+            "Timed out at frames {:?}",
+            peers
+                .iter()
+                .map(|peer| peer.sim.state.frame)
+                .collect::<Vec<_>>()
+        );
+        // Finished peers keep polling, the others may still need their inputs resent.
+        for (peer, last_frame) in peers.iter_mut().zip(last_frames) {
+            peer.update(*last_frame);
+        }
+        for (client, peer) in clients.iter_mut().zip(peers.iter_mut()) {
+            peer.send_recorded(client, false);
+        }
+        for client in idle.iter_mut() {
+            client.keep_alive();
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    for (client, peer) in clients.iter_mut().zip(peers.iter_mut()) {
+        peer.send_recorded(client, true);
+    }
+}
+
+fn assert_agree(peers: &[Peer], frames: std::ops::RangeInclusive<u32>) {
+    for frame in frames {
+        let checksums: Vec<u64> = peers.iter().map(|peer| peer.checksums[&frame]).collect();
+        assert!(
+            checksums.iter().all(|checksum| *checksum == checksums[0]),
+            "Peers disagree on frame {}: {:?}",
+            frame,
+            checksums
+        );
+    }
+}
+
+/// Plays until every peer's session saw the match end on a confirmed frame, and returns their
+/// reports of it, the way the game and the bots do.
+fn play_to_the_end(clients: &mut [Client], peers: &mut [Peer]) -> Vec<shared::MatchReport> {
+    let deadline = Instant::now() + TIMEOUT;
+    while peers.iter().any(|peer| peer.session.ended().is_none()) {
+        assert!(
+            Instant::now() < deadline,
+            "Timed out fighting at frames {:?}",
+            peers
+                .iter()
+                .map(|peer| peer.sim.state.frame)
+                .collect::<Vec<_>>()
+        );
+        // Peers whose match ended keep polling, the others may still need their inputs.
+        for peer in peers.iter_mut() {
+            peer.update(u32::MAX);
+        }
+        for (client, peer) in clients.iter_mut().zip(peers.iter_mut()) {
+            let ended = peer.session.ended().is_some();
+            peer.send_recorded(client, ended);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    peers
+        .iter()
+        .map(|peer| peer.session.ended().unwrap().clone())
+        .collect()
+}
+
+/// Every peer's report of a scripted match, which never ends, as its session last simulated
+/// `frame`. The frames after it may still be predicted, so peers could disagree on them.
+fn peer_reports(peers: &[Peer], frame: u32) -> Vec<shared::MatchReport> {
+    peers
+        .iter()
+        .map(|peer| {
+            peer.session
+                .history()
+                .find(|report| report.frame == frame)
+                .expect("The session should remember the frame")
+                .clone()
+        })
+        .collect()
+}
+
+/// Sends the reports and returns the results, which every client has to get the same of.
+fn finish(clients: &mut [Client], reports: Vec<shared::MatchReport>) -> shared::MatchResults {
+    for (client, report) in clients.iter_mut().zip(reports) {
+        client.send(&shared::ClientMsg::MatchOver(report));
+    }
+    let results: Vec<shared::MatchResults> = clients
+        .iter_mut()
+        .map(|client| {
+            client.wait_for("MatchResults", |msg| match msg {
+                shared::ServerMsg::MatchResults(results) => Some(results),
+                _ => None,
+            })
+        })
+        .collect();
+    assert!(results.iter().all(|result| *result == results[0]));
+    results.into_iter().next().unwrap()
+}
+
+/// The results of a match of `players` peers where nobody shoots.
+fn peaceful_results(players: usize, disputed: bool) -> shared::MatchResults {
+    shared::MatchResults {
+        winner: None,
+        scoreboard: (0..players).map(|i| (format!("Peer {}", i), 0)).collect(),
+        disputed,
+    }
+}
+
+/// Replays the match from the inputs the server relays to `spectator`, until the server sends
+/// the results. Returns the checksum after frame `FRAMES`.
+fn watch(spectator: &mut Client, start: &shared::Start, map: TileMap) -> u64 {
+    let watched = spectator.wait_for("Start", |msg| match msg {
+        shared::ServerMsg::Start(start) => Some(start),
+        _ => None,
+    });
+    assert_eq!(
+        watched, *start,
+        "The spectator should get the players' Start"
+    );
+    let mut inputs: Vec<Vec<Input>> = vec![vec![]; start.players.len()];
+    spectator.wait_for("MatchResults", |msg| match msg {
+        shared::ServerMsg::Inputs(batch) => {
+            let recorded = &mut inputs[batch.player as usize];
+            assert_eq!(
+                batch.first_frame as usize,
+                recorded.len(),
+                "Inputs should be relayed in order, without gaps"
+            );
+            recorded.extend(
+                batch
+                    .inputs
+                    .iter()
+                    .map(|bits| Input::from_bits_truncate(*bits)),
+            );
+            None
+        }
+        shared::ServerMsg::MatchResults(_) => Some(()),
+        _ => None,
+    });
+    let mut sim = Sim::new(&start.settings, map, &spawns(start)).unwrap();
+    for frame in 0..FRAMES as usize {
+        sim.advance(|id| inputs[id as usize][frame]);
+    }
+    sim.state.checksum()
+}
+
+/// Has `players` clients join a lobby, ready up, play `FRAMES` frames and report the result,
+/// checking the lobby protocol along the way. Another client spectates the match.
+fn play_match(players: usize, conditions: Option<netsim::Conditions>) {
+    let (map, server_addr) = spawn_server();
+    let task_pool = TaskPool::new();
+
+    let mut clients: Vec<Client> = (0..players)
+        .map(|_| Client::connect(server_addr, map.hash, &task_pool))
+        .collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.join(i);
+    }
+    let mut spectator = Client::connect(server_addr, map.hash, &task_pool);
+    spectator.send(&shared::ClientMsg::Spectate(shared::Spectate {
+        lobby: LOBBY.to_string(),
+        password: String::new(),
+    }));
+
+    for client in &mut clients {
+        client.send(&shared::ClientMsg::Ready);
+    }
+    let mut starts = vec![];
+    for client in &mut clients {
+        let mut full_roster = false;
+        let mut counted_down = false;
+        let start = client.wait_for("Start", |msg| match msg {
+            shared::ServerMsg::LobbyUpdate(update) => {
+                full_roster |= update.players.len() == players;
+                None
+            }
+            shared::ServerMsg::Countdown(_) => {
+                counted_down = true;
+                None
+            }
+            shared::ServerMsg::Start(start) => Some(start),
+            _ => None,
+        });
+        assert!(full_roster, "Every player should have been in the lobby");
+        assert!(
+            counted_down,
+            "The match should have started with a countdown"
+        );
+        assert_eq!(start.players.len(), players);
+        starts.push(start);
+    }
+    assert!(
+        starts.iter().all(|start| *start == starts[0]),
+        "Every player should get the same Start"
+    );
+
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .zip(&starts)
+        .map(|(client, start)| {
+            Peer::new(
+                client,
+                start,
+                TileMap::from_info(&map),
+                conditions.as_ref(),
+                &task_pool,
+            )
+        })
+        .collect();
+    play_until(
+        &mut clients,
+        &mut peers,
+        std::slice::from_mut(&mut spectator),
+        &vec![FRAMES + SETTLE_FRAMES; players],
+    );
+    assert_agree(&peers, 1..=FRAMES);
+    let mut reports = peer_reports(&peers, FRAMES);
+    // With more than two players, one claiming a kill it did not make is outvoted.
+    let cheat = players > 2;
+    if cheat {
+        reports[0].kills[0] += 1;
+    }
+    assert_eq!(
+        finish(&mut clients, reports),
+        peaceful_results(players, cheat)
+    );
+
+    assert_eq!(
+        watch(&mut spectator, &starts[0], TileMap::from_info(&map)),
+        peers[0].checksums[&FRAMES],
+        "The spectator should see the same match"
+    );
+}
+
+/// Two bots fight until one of them wins. With `cheat`, one of them claims a kill it did not
+/// make, and without a majority the server can not tell who is right.
+fn duel(cheat: bool) {
+    let (map, server_addr) = spawn_server();
+    let task_pool = TaskPool::new();
+
+    let mut clients: Vec<Client> = (0..2)
+        .map(|_| Client::connect(server_addr, map.hash, &task_pool))
+        .collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.join(i);
+        client.send(&shared::ClientMsg::Ready);
+    }
+    let starts = wait_for_starts(&mut clients);
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .zip(&starts)
+        .map(|(client, start)| {
+            let mut peer = Peer::new(client, start, TileMap::from_info(&map), None, &task_pool);
+            peer.fight(start);
+            peer
+        })
+        .collect();
+    let mut reports = play_to_the_end(&mut clients, &mut peers);
+    assert!(
+        reports.iter().all(|report| *report == reports[0]),
+        "Peers should report the same end: {:?}",
+        reports
+    );
+    let report = reports[0].clone();
+    assert!(report.winner.is_some(), "A bot should have won");
+    let expected = if cheat {
+        reports[0].kills[0] += 1;
+        shared::MatchResults {
+            winner: None,
+            scoreboard: vec![],
+            disputed: true,
+        }
+    } else {
+        let name = |id: usize| starts[0].players[id].name.clone();
+        shared::MatchResults {
+            winner: report.winner.map(|winner| name(winner as usize)),
+            scoreboard: report
+                .kills
+                .iter()
+                .enumerate()
+                .map(|(id, kills)| (name(id), *kills))
+                .collect(),
+            disputed: false,
+        }
+    };
+    assert_eq!(finish(&mut clients, reports), expected);
+}
+
+#[test]
+fn duel_reports_the_winner() {
+    duel(false);
+}
+
+#[test]
+fn duel_with_a_cheat_is_disputed() {
+    duel(true);
+}
+
+#[test]
+fn match_on_a_perfect_network() {
+    play_match(2, None);
+}
+
+#[test]
+fn match_on_a_bad_network() {
+    play_match(
+        3,
+        Some(netsim::Conditions {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(10),
+            loss: 5,
+            duplicate: 2,
+            reorder: 5,
+        }),
+    );
+}
+
+/// A player joins midway, while the peers are at different frames. The server pauses the match
+/// at a frame ahead of both, replays it up to there and resumes it with the joiner.
+#[test]
+fn match_resumes_with_a_late_joiner() {
+    let (map, server_addr) = spawn_server();
+    let task_pool = TaskPool::new();
+
+    let mut clients: Vec<Client> = (0..2)
+        .map(|_| Client::connect(server_addr, map.hash, &task_pool))
+        .collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.join(i);
+        client.send(&shared::ClientMsg::Ready);
+    }
+    let starts = wait_for_starts(&mut clients);
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .zip(&starts)
+        .map(|(client, start)| Peer::new(client, start, TileMap::from_info(&map), None, &task_pool))
+        .collect();
+    play_until(&mut clients, &mut peers, &mut [], &[HANDOFF_FRAME; 2]);
+    // The first peer runs ahead on predicted inputs of the second.
+    play_until(
+        &mut clients,
+        &mut peers,
+        &mut [],
+        &[HANDOFF_FRAME + AHEAD_FRAMES, HANDOFF_FRAME],
+    );
+
+    let mut joiner = Client::connect(server_addr, map.hash, &task_pool);
+    joiner.join(2);
+    let pause_frames: Vec<u32> = clients
+        .iter_mut()
+        .map(|client| {
+            client.wait_for("Pause", |msg| match msg {
+                shared::ServerMsg::Pause(frame) => Some(frame),
+                _ => None,
+            })
+        })
+        .collect();
+    let pause_frame = pause_frames[0];
+    assert!(
+        pause_frames.iter().all(|frame| *frame == pause_frame),
+        "Every player should pause at the same frame"
+    );
+    assert!(
+        pause_frame > HANDOFF_FRAME + AHEAD_FRAMES,
+        "The match should pause ahead of every player"
+    );
+    play_until(
+        &mut clients,
+        &mut peers,
+        std::slice::from_mut(&mut joiner),
+        &[pause_frame; 2],
+    );
+    for client in &mut clients {
+        client.send(&shared::ClientMsg::Paused);
+    }
+    clients.push(joiner);
+    let resumed = wait_for_starts(&mut clients).remove(0);
+    assert_eq!(resumed.players.len(), 3);
+    assert_eq!(resumed.settings, starts[0].settings);
+
+    // Both players played exactly up to the pause, so the server has every input.
+    let old = &starts[0];
+    let mut expected = Sim::new(&old.settings, TileMap::from_info(&map), &spawns(old)).unwrap();
+    while expected.state.frame < pause_frame {
+        let frame = expected.state.frame;
+        expected.advance(|id| script(frame, id));
+    }
+    let roster: Vec<Option<u32>> = resumed
+        .players
+        .iter()
+        .map(|player| {
+            old.players
+                .iter()
+                .position(|before| (&before.ip, before.port) == (&player.ip, player.port))
+                .map(|id| id as u32)
+        })
+        .collect();
+    assert_eq!(roster, [Some(0), Some(1), None]);
+    expected.change_roster(&roster, &spawns(&resumed));
+    assert_eq!(
+        resumed.resume,
+        Some(expected.snapshot()),
+        "The match should resume where the players stopped"
+    );
+
+    drop(peers);
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .map(|client| Peer::new(client, &resumed, TileMap::from_info(&map), None, &task_pool))
+        .collect();
+    play_until(
+        &mut clients,
+        &mut peers,
+        &mut [],
+        &[pause_frame + FRAMES + SETTLE_FRAMES; 3],
+    );
+    assert_agree(&peers, pause_frame + 1..=pause_frame + FRAMES);
+    let reports = peer_reports(&peers, pause_frame + FRAMES);
+    assert_eq!(finish(&mut clients, reports), peaceful_results(3, false));
+}
+
+/// A player leaves in the middle of a batch of inputs. The server only has the batches they
+/// sent, so its replay has them stand still from there on, and the others go on from it.
+#[test]
+fn match_resumes_without_a_player_who_left_mid_batch() {
+    let (map, server_addr) = spawn_server();
+    let task_pool = TaskPool::new();
+
+    let mut clients: Vec<Client> = (0..3)
+        .map(|_| Client::connect(server_addr, map.hash, &task_pool))
+        .collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.join(i);
+        client.send(&shared::ClientMsg::Ready);
+    }
+    let starts = wait_for_starts(&mut clients);
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .zip(&starts)
+        .map(|(client, start)| Peer::new(client, start, TileMap::from_info(&map), None, &task_pool))
+        .collect();
+    play_until(&mut clients, &mut peers, &mut [], &[HANDOFF_FRAME; 3]);
+    // Only the first two clients get to send these inputs.
+    let partial = shared::INPUT_BATCH_FRAMES as u32 / 2;
+    play_until(
+        &mut clients[..2],
+        &mut peers,
+        &mut [],
+        &[HANDOFF_FRAME + partial; 3],
+    );
+    drop(peers.pop());
+    drop(clients.pop());
+
+    let pause_frames: Vec<u32> = clients
+        .iter_mut()
+        .map(|client| {
+            client.wait_for("Pause", |msg| match msg {
+                shared::ServerMsg::Pause(frame) => Some(frame),
+                _ => None,
+            })
+        })
+        .collect();
+    let pause_frame = pause_frames[0];
+    assert!(pause_frames.iter().all(|frame| *frame == pause_frame));
+    play_until(&mut clients, &mut peers, &mut [], &[pause_frame; 2]);
+    for client in &mut clients {
+        client.send(&shared::ClientMsg::Paused);
+    }
+    let resumed = wait_for_starts(&mut clients).remove(0);
+    assert_eq!(resumed.players.len(), 2);
+
+    let old = &starts[0];
+    let mut expected = Sim::new(&old.settings, TileMap::from_info(&map), &spawns(old)).unwrap();
+    while expected.state.frame < pause_frame {
+        let frame = expected.state.frame;
+        expected.advance(|id| {
+            if id == 2 && frame >= HANDOFF_FRAME {
+                Input::empty()
+            } else {
+                script(frame, id)
+            }
+        });
+    }
+    expected.change_roster(&[Some(0), Some(1)], &spawns(&resumed));
+    assert_eq!(
+        resumed.resume,
+        Some(expected.snapshot()),
+        "The leaver should stand still from their last batch on"
+    );
+
+    drop(peers);
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .map(|client| Peer::new(client, &resumed, TileMap::from_info(&map), None, &task_pool))
+        .collect();
+    play_until(
+        &mut clients,
+        &mut peers,
+        &mut [],
+        &[pause_frame + FRAMES + SETTLE_FRAMES; 2],
+    );
+    assert_agree(&peers, pause_frame + 1..=pause_frame + FRAMES);
+    let reports = peer_reports(&peers, pause_frame + FRAMES);
+    assert_eq!(finish(&mut clients, reports), peaceful_results(2, false));
+}
+
+/// A player leaves their lobby's match for another lobby. That one's match has to start for
+/// them like for everyone else, and its results arrive once it ends.
+#[test]
+fn players_can_leave_a_match_for_another_lobby() {
+    let (map, server_addr) = spawn_server();
+    let task_pool = TaskPool::new();
+
+    let mut first: Vec<Client> = (0..2)
+        .map(|_| Client::connect(server_addr, map.hash, &task_pool))
+        .collect();
+    for (i, client) in first.iter_mut().enumerate() {
+        client.join(i);
+        client.send(&shared::ClientMsg::Ready);
+    }
+    wait_for_starts(&mut first);
+    let mut leaver = first.remove(0);
+    leaver.send(&shared::ClientMsg::Leave);
+
+    let mut clients = vec![leaver, Client::connect(server_addr, map.hash, &task_pool)];
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.join_lobby(i, "other");
+        client.send(&shared::ClientMsg::Ready);
+    }
+    let starts = wait_for_starts(&mut clients);
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .zip(&starts)
+        .map(|(client, start)| Peer::new(client, start, TileMap::from_info(&map), None, &task_pool))
+        .collect();
+    play_until(
+        &mut clients,
+        &mut peers,
+        &mut first,
+        &[FRAMES + SETTLE_FRAMES; 2],
+    );
+    assert_agree(&peers, 1..=FRAMES);
+    let reports = peer_reports(&peers, FRAMES);
+    assert_eq!(finish(&mut clients, reports), peaceful_results(2, false));
+}
+
+/// Two players share one machine, which plays them in one session and sends each one's inputs
+/// over their own lobby connection, against a third player on another machine.
+#[test]
+fn players_share_a_machine() {
+    let (map, server_addr) = spawn_server();
+    let task_pool = TaskPool::new();
+
+    let mut clients: Vec<Client> = (0..3)
+        .map(|_| Client::connect(server_addr, map.hash, &task_pool))
+        .collect();
+    let (host, rest) = clients.split_at_mut(1);
+    rest[0].share_machine(&host[0], 1);
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.join(i);
+    }
+    let mut spectator = Client::connect(server_addr, map.hash, &task_pool);
+    spectator.send(&shared::ClientMsg::Spectate(shared::Spectate {
+        lobby: LOBBY.to_string(),
+        password: String::new(),
+    }));
+    for client in &mut clients {
+        client.send(&shared::ClientMsg::Ready);
+    }
+    let starts = wait_for_starts(&mut clients);
+    let locals: Vec<u8> = starts[0]
+        .players
+        .iter()
+        .map(|player| player.local)
+        .collect();
+    assert_eq!(locals, [0, 1, 0]);
+
+    let guest = clients.remove(1);
+    let mut peers: Vec<Peer> = clients
+        .iter()
+        .zip(&starts)
+        .map(|(client, start)| Peer::new(client, start, TileMap::from_info(&map), None, &task_pool))
+        .collect();
+    peers[0].seat_guest(&starts[0], guest);
+    assert_eq!(peers[0].session.local_players(), [0, 1]);
+    play_until(
+        &mut clients,
+        &mut peers,
+        std::slice::from_mut(&mut spectator),
+        &[FRAMES + SETTLE_FRAMES; 2],
+    );
+    assert_agree(&peers, 1..=FRAMES);
+    let reports = peer_reports(&peers, FRAMES);
+    let (_, guest) = peers[0].guests.pop().unwrap();
+    clients.insert(1, guest);
+    let reports = vec![reports[0].clone(), reports[0].clone(), reports[1].clone()];
+    assert_eq!(finish(&mut clients, reports), peaceful_results(3, false));
+
+    assert_eq!(
+        watch(&mut spectator, &starts[0], TileMap::from_info(&map)),
+        peers[0].checksums[&FRAMES],
+        "The spectator should see the same match"
+    );
+}
+
+/// The body of the metrics endpoint's answer to `GET path`.
+fn scrape(port: u16, path: &str) -> String {
+    let deadline = Instant::now() + TIMEOUT;
+    // The metrics thread may not be listening yet.
+    let mut stream = loop {
+        match TcpStream::connect((Ipv4Addr::LOCALHOST, port)) {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            Err(err) => panic!("Could not connect to the metrics endpoint: {}", err),
+        }
+    };
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    response.split("\r\n\r\n").nth(1).unwrap().to_string()
+}
+
+#[test]
+fn metrics_describe_the_lobbies() {
+    let metrics_port = free_port();
+    let (map, server_addr) = spawn_server_with(Some(metrics_port));
+    let task_pool = TaskPool::new();
+    let mut client = Client::connect(server_addr, map.hash, &task_pool);
+    client.join(0);
+    // Refusals are not protocol errors, messages out of place are.
+    let mut outsider = Client::connect(server_addr, map.hash, &task_pool);
+    outsider.send(&shared::ClientMsg::Spectate(shared::Spectate {
+        lobby: "nowhere".to_string(),
+        password: String::new(),
+    }));
+    outsider.wait_for_error(shared::Error::NoSuchLobby);
+    client.send(&shared::ClientMsg::Hello(shared::Hello {
+        version: shared::PROTOCOL_VERSION,
+        build: shared::build_hash(),
+        map: map.hash,
+    }));
+    let deadline = Instant::now() + TIMEOUT;
+    while !scrape(metrics_port, "/metrics").contains("fishgame_protocol_errors_total 1\n") {
+        assert!(
+            Instant::now() < deadline,
+            "Timed out waiting for the protocol error"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let status = scrape(metrics_port, "/status");
+    assert!(
+        status.contains(&format!(r#""code":"{}","players":1,"#, LOBBY)),
+        "{}",
+        status
+    );
+    assert!(status.contains(r#""joins":1,"#), "{}", status);
+    let metrics = scrape(metrics_port, "/metrics");
+    let lobby_players = format!("fishgame_lobby_players{{lobby=\"{}\"}} 1\n", LOBBY);
+    for line in &[
+        "# TYPE fishgame_uptime_seconds gauge\n",
+        "fishgame_lobbies 1\n",
+        lobby_players.as_str(),
+        "fishgame_joins_total 1\n",
+        "# TYPE fishgame_protocol_errors_total counter\n",
+    ] {
+        assert!(
+            metrics.contains(*line),
+            "{} is missing from\n{}",
+            line,
+            metrics
+        );
+    }
+}
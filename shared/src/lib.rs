@@ -1,7 +1,352 @@
 use nanoserde::{DeBin, SerBin};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 
+pub mod map;
+pub mod rng;
+
+/// Bumped whenever the wire format of `ClientMsg` or `ServerMsg` changes.
+pub const PROTOCOL_VERSION: u32 = 17;
+
+/// 64-bit FNV-1a. Used for build and map hashes, which have to agree across platforms.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Identifies the build, so that clients and servers built from different sources refuse to
+/// play together. Set `FISHGAME_BUILD_ID` at compile time (e.g. to a commit hash) to make it
+/// stricter than the crate version.
+pub fn build_hash() -> u64 {
+    content_hash(
+        option_env!("FISHGAME_BUILD_ID")
+            .unwrap_or(env!("CARGO_PKG_VERSION"))
+            .as_bytes(),
+    )
+}
+
+/// The address other players can reach us on: the one our traffic to the lobby server leaves
+/// from.
+pub fn local_ip_towards(server_addr: SocketAddr) -> IpAddr {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect(server_addr)?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .ok()
+        .filter(|ip| !ip.is_unspecified())
+        .unwrap_or_else(|| Ipv4Addr::LOCALHOST.into())
+}
+
+/// First message on every connection.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Hello {
+    pub version: u32,
+    pub build: u64,
+    /// `content_hash` of the map JSON. The server refuses clients with a different map than its
+    /// matches are played on, so that everyone who gets a `Start` can play it.
+    pub map: u64,
+}
+
+/// Which lobby a client wants to be put into.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub enum LobbyChoice {
+    /// Create a fresh lobby with a join code picked by the server.
+    New,
+    /// Join the lobby with this name or join code, creating it if it does not exist yet.
+    Named(String),
+}
+
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Join {
+    /// The address other players can reach the client on for the match. Players sharing a
+    /// machine join on connections of their own, but with the same address.
+    pub ip: String,
+    pub port: u16,
+    /// Which of the players sharing the machine this is, 0 for the first.
+    pub local: u8,
+    pub lobby: LobbyChoice,
+    /// Display name shown to the other players.
+    pub name: String,
+    /// Preferred color as RGB.
+    pub color: (u8, u8, u8),
+    /// Has to match the lobby's password, if it has one. When the join creates the lobby, a
+    /// non-empty password protects it.
+    pub password: String,
+    /// When the join creates the lobby, keeps it out of `LobbyList`. Players need its name or
+    /// join code to find it.
+    pub private: bool,
+}
+
+/// Sent in reply to `Join`, with the code other players can use to join the same lobby.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Joined {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct LobbyPlayer {
+    pub name: String,
+    pub color: (u8, u8, u8),
+    pub ready: bool,
+    pub host: bool,
+}
+
+/// Everyone currently in the lobby, resent whenever someone joins, leaves or changes readiness.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct LobbyUpdate {
+    pub players: Vec<LobbyPlayer>,
+}
+
+/// One entry of `ServerMsg::LobbyList`.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct LobbyInfo {
+    /// Name or join code, to be passed to `LobbyChoice::Named`.
+    pub name: String,
+    pub map: String,
+    pub players: u32,
+    pub max_players: u32,
+    /// A match is being played. Players who join take part in it from the next handoff.
+    pub in_progress: bool,
+    /// Joining needs a password.
+    pub password: bool,
+}
+
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct StartPlayer {
+    pub ip: String,
+    pub port: u16,
+    /// `Join::local` of the player.
+    pub local: u8,
+    /// Spawn position in pixels.
+    pub spawn: (i32, i32),
+    pub name: String,
+    pub color: (u8, u8, u8),
+}
+
+/// Movement and health. Speeds are in pixels per second, accelerations in pixels per second
+/// squared.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Rules {
+    pub gravity: f32,
+    pub jump_speed: f32,
+    pub run_speed: f32,
+    pub max_health: i32,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            gravity: 900.0,
+            jump_speed: 250.0,
+            run_speed: 150.0,
+            max_health: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Weapon {
+    pub bullet_speed: f32,
+    /// Seconds a bullet flies before it disappears.
+    pub bullet_lifetime: f32,
+    /// Ticks between shots while the trigger is held.
+    pub interval_ticks: u32,
+    pub damage: i32,
+    /// Largest vertical speed a bullet randomly gets, as a fraction of `bullet_speed`.
+    pub spread: f32,
+    /// Percent chance that a hit does double damage.
+    pub crit_chance: u32,
+}
+
+impl Weapon {
+    pub fn pistol() -> Self {
+        Self {
+            bullet_speed: 300.0,
+            bullet_lifetime: 0.7,
+            interval_ticks: 10,
+            damage: 5,
+            spread: 0.0,
+            crit_chance: 0,
+        }
+    }
+}
+
+/// Everything the clients have to agree on to simulate the same match.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct MatchSettings {
+    pub seed: u64,
+    /// Name of the map, as in `map::MapInfo::name`.
+    pub map: String,
+    /// `content_hash` of the map JSON, the one `Hello` checked.
+    pub map_hash: u64,
+    /// Simulation ticks per second.
+    pub tick_rate: u32,
+    pub rules: Rules,
+    /// Weapons available in the match, at least one. Each player spawns with a random one.
+    pub weapons: Vec<Weapon>,
+    /// Kills that win the match. With 0 the match goes on until one player is left.
+    pub score_limit: u32,
+}
+
+/// Starts a match, or resumes it with a new roster after players joined or left: the server
+/// pauses the match with `ServerMsg::Pause` and then sends everyone a `Start` with a `resume`
+/// snapshot.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Start {
+    pub players: Vec<StartPlayer>,
+    pub settings: MatchSettings,
+    /// `sim::Sim::snapshot` of the match to resume, whose players are indexed by `players`.
+    pub resume: Option<Vec<u8>>,
+}
+
+/// Sent by each client once its match is over. Clients that simulated the same match send the
+/// same report, so the server can tell a desync or a cheating client from the others.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct MatchReport {
+    /// Index into `Start` of the last player standing, if anyone survived.
+    pub winner: Option<u32>,
+    /// Kills of each player, indexed like `Start::players`.
+    pub kills: Vec<u32>,
+    /// The frame the match ended on, and `sim::State::checksum` after it.
+    pub frame: u32,
+    pub state_hash: u64,
+}
+
+/// Sent once every player reported the end of the match, or left. The lobby then waits for
+/// everyone to be ready again for a rematch with the same roster.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct MatchResults {
+    /// Name of the winner, if there was one.
+    pub winner: Option<String>,
+    /// Names and kills of the match players.
+    pub scoreboard: Vec<(String, u32)>,
+    /// Whether the players' reports differed. The results are then those more than half the
+    /// players reported, and without such a majority there is no winner or scoreboard.
+    pub disputed: bool,
+}
+
+/// Asks to watch the matches of a lobby without playing in them.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Spectate {
+    /// Name or join code of a lobby that exists.
+    pub lobby: String,
+    /// Has to match the lobby's password, if it has one.
+    pub password: String,
+}
+
+/// Players send their inputs to the lobby server in batches of this many frames.
+pub const INPUT_BATCH_FRAMES: usize = 10;
+
+/// Inputs of one match player for consecutive frames, as `sim::Input` bits. Players send their
+/// own, and the lobby server relays everyone's to spectators.
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub struct Inputs {
+    /// Index into `Start`. Ignored in inputs from players, the server knows who they are.
+    pub player: u32,
+    pub first_frame: u32,
+    pub inputs: Vec<u8>,
+}
+
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub enum ClientMsg {
+    /// Must stay the first variant with `version` as its first field, so that the server can
+    /// still read it from clients with a different protocol version.
+    Hello(Hello),
+    Join(Join),
+    Ready,
+    Unready,
+    /// Asks the server to start the countdown. Only the lobby host may do this, and only once
+    /// every player is ready.
+    StartRequest,
+    /// Keeps the connection alive while nothing else is sent. The server drops clients that
+    /// stay silent for a few seconds.
+    Heartbeat,
+    Leave,
+    /// Asks for a `LobbyList`. Can be sent before `Join`.
+    ListLobbies,
+    MatchOver(MatchReport),
+    /// Sent instead of `Join`. The server then sends the lobby's `Start`, `Inputs` and
+    /// `MatchResults` of every match, including the one being played.
+    Spectate(Spectate),
+    /// Our player's inputs during a match, for spectators and handoffs.
+    Inputs(Inputs),
+    /// Reply to `Pause`, once we reached its frame and sent every input we played.
+    Paused,
+}
+
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub enum ServerMsg {
+    /// Reply to a `Hello` the server accepted.
+    Welcome,
+    Error(Error),
+    Joined(Joined),
+    LobbyUpdate(LobbyUpdate),
+    /// Seconds left until the match starts.
+    Countdown(u8),
+    CountdownCancelled,
+    Start(Start),
+    LobbyList(Vec<LobbyInfo>),
+    MatchResults(MatchResults),
+    /// Inputs of a match player, relayed to spectators.
+    Inputs(Inputs),
+    /// Players joined or left the match. Play up to this frame, or stop where we are if we
+    /// are past it, and answer with `ClientMsg::Paused`; the match goes on from this frame with
+    /// the `Start` that follows.
+    Pause(u32),
+}
+
+#[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
+pub enum Error {
+    /// The server speaks this protocol version instead. The server disconnects afterwards.
+    VersionMismatch(u32),
+    /// The client was built from different sources. The server disconnects afterwards.
+    BuildMismatch,
+    /// The client has a different map. The server disconnects afterwards.
+    MapMismatch,
+    LobbyFull,
+    /// The lobby has a password and `Join` did not provide it.
+    WrongPassword,
+    /// The lobby's match is ending; it can be joined once the results are in.
+    MatchInProgress,
+    /// The message could not be decoded.
+    BadMessage,
+    /// The message is not valid at this point, e.g. `Ready` before `Join`.
+    UnexpectedMessage,
+    /// Nothing was heard from the client for too long. The server disconnects afterwards.
+    TimedOut,
+    /// An admin removed the client from its lobby. The server disconnects afterwards.
+    Kicked,
+    /// There is no lobby with the name given to `Spectate`, or it closed while spectating.
+    NoSuchLobby,
+}
+
+/// UDP port lobby servers listen on for `Discovery::Probe`, so that clients can find them on
+/// the local network without knowing their address.
+pub const DISCOVERY_PORT: u16 = 8093;
+
+/// What a lobby server tells clients looking for servers on the local network. The server's
+/// address is the one the announcement came from.
 #[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
-pub struct Join(pub u16);
+pub struct Announcement {
+    pub name: String,
+    /// The lobby server's TCP port.
+    pub port: u16,
+    pub version: u32,
+    /// Players waiting in the server's lobbies.
+    pub players: u32,
+    pub map: String,
+    /// `content_hash` of the map JSON.
+    pub map_hash: u64,
+}
 
+/// Datagrams on `DISCOVERY_PORT`. Clients broadcast `Probe`, servers answer with `Announce`.
 #[derive(Debug, Clone, SerBin, DeBin, PartialEq)]
-pub struct Start(pub Vec<(u16, (u16, u8))>);
+pub enum Discovery {
+    Probe,
+    Announce(Announcement),
+}
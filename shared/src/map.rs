@@ -0,0 +1,206 @@
+//! Match data from the Tiled map that the lobby server and offline matches both need.
+//!
+//! The tiles of the `main layer` are solid wherever the layer has one.
+//!
+//! Spawn points are the objects of the `logic` layer. They can be tuned with custom
+//! properties of the `int` or `string` type in Tiled:
+//!
+//! * `priority` (default `0`): spawns with a higher priority are handed out first.
+//! * `min_players` (default `0`): the spawn is only used in matches with at least this many
+//!   players.
+
+use nanoserde::{DeJson, DeJsonErr, DeJsonState, DeJsonTok};
+use std::str::Chars;
+
+#[derive(DeJson)]
+struct TiledMap {
+    width: i32,
+    tilewidth: i32,
+    layers: Vec<TiledLayer>,
+}
+
+#[derive(DeJson)]
+struct TiledLayer {
+    name: String,
+    #[nserde(default)]
+    data: Vec<u32>,
+    #[nserde(default)]
+    objects: Vec<TiledObject>,
+}
+
+#[derive(DeJson)]
+struct TiledObject {
+    x: f32,
+    y: f32,
+    #[nserde(default)]
+    properties: Vec<TiledProperty>,
+}
+
+#[derive(DeJson)]
+struct TiledProperty {
+    name: String,
+    value: PropertyValue,
+}
+
+/// Tiled writes property values as JSON strings, numbers or booleans depending on the
+/// property's type. They are all kept as text, for `str::parse`.
+struct PropertyValue(String);
+
+impl DeJson for PropertyValue {
+    fn de_json(state: &mut DeJsonState, input: &mut Chars) -> Result<Self, DeJsonErr> {
+        let value = match state.tok {
+            DeJsonTok::U64(value) => value.to_string(),
+            DeJsonTok::I64(value) => value.to_string(),
+            DeJsonTok::F64(value) => value.to_string(),
+            DeJsonTok::Bool(value) => value.to_string(),
+            _ => state.as_string()?,
+        };
+        state.next_tok(input)?;
+        Ok(Self(value))
+    }
+}
+
+/// The name of the map stored at `path`: its file name without the extension.
+pub fn name_from_path(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_stem()
+        .map_or("map".into(), |stem| stem.to_string_lossy().into_owned())
+}
+
+pub struct Spawn {
+    pub x: f32,
+    pub y: f32,
+    pub priority: i32,
+    pub min_players: usize,
+}
+
+pub struct MapInfo {
+    pub name: String,
+    /// `crate::content_hash` of the map JSON.
+    pub hash: u64,
+    pub spawns: Vec<Spawn>,
+    /// Width of the map in tiles.
+    pub width: i32,
+    /// In pixels.
+    pub tile_size: i32,
+    /// Which tiles of the main layer are solid, row by row, as `sim::TileMap` takes them.
+    pub solid: Vec<bool>,
+}
+
+impl MapInfo {
+    pub fn parse(name: &str, json: &str) -> Result<Self, String> {
+        let map: TiledMap =
+            DeJson::deserialize_json(json).map_err(|err| format!("Invalid map: {:?}", err))?;
+        if map.width <= 0 || map.tilewidth <= 0 {
+            return Err("The map has no width or tile size".to_string());
+        }
+        let solid = map
+            .layers
+            .iter()
+            .find(|layer| layer.name == "main layer")
+            .ok_or("The map has no main layer")?
+            .data
+            .iter()
+            .map(|tile| *tile != 0)
+            .collect();
+        let logic = map
+            .layers
+            .into_iter()
+            .find(|layer| layer.name == "logic")
+            .ok_or("The map has no logic layer")?;
+        let spawns = logic
+            .objects
+            .into_iter()
+            .map(|object| {
+                let mut spawn = Spawn {
+                    x: object.x,
+                    y: object.y,
+                    priority: 0,
+                    min_players: 0,
+                };
+                for property in object.properties {
+                    let invalid = |_| {
+                        format!(
+                            "Invalid {} {:?} on the spawn at ({}, {})",
+                            property.name, property.value.0, spawn.x, spawn.y
+                        )
+                    };
+                    match property.name.as_str() {
+                        "priority" => spawn.priority = property.value.0.parse().map_err(invalid)?,
+                        "min_players" => {
+                            spawn.min_players = property.value.0.parse().map_err(invalid)?
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(spawn)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if spawns.is_empty() {
+            return Err("The map has no spawn points".to_string());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            hash: crate::content_hash(json.as_bytes()),
+            spawns,
+            width: map.width,
+            tile_size: map.tilewidth,
+            solid,
+        })
+    }
+
+    /// How many players fit on the map without sharing a spawn point.
+    pub fn max_players(&self) -> usize {
+        self.spawns.len()
+    }
+
+    /// Picks a spawn point for each of `count` players, as far apart as possible. Spawns are
+    /// handed out by priority, and within the same priority each next spawn is the one
+    /// farthest from all spawns picked so far. Spawns are only reused once every usable one is
+    /// taken.
+    pub fn assign_spawns(&self, count: usize) -> Vec<(f32, f32)> {
+        let mut candidates: Vec<&Spawn> = self
+            .spawns
+            .iter()
+            .filter(|spawn| spawn.min_players <= count)
+            .collect();
+        if candidates.is_empty() {
+            candidates = self.spawns.iter().collect();
+        }
+
+        let mut picked: Vec<&Spawn> = vec![];
+        while picked.len() < count.min(candidates.len()) {
+            let best_priority = candidates
+                .iter()
+                .filter(|spawn| !picked.iter().any(|other| std::ptr::eq(*other, **spawn)))
+                .map(|spawn| spawn.priority)
+                .max()
+                .unwrap();
+            let distance_to_picked = |spawn: &Spawn| {
+                picked
+                    .iter()
+                    .map(|other| (other.x - spawn.x).powi(2) + (other.y - spawn.y).powi(2))
+                    .fold(f32::INFINITY, f32::min)
+            };
+            let next = candidates
+                .iter()
+                .filter(|spawn| spawn.priority == best_priority)
+                .filter(|spawn| !picked.iter().any(|other| std::ptr::eq(*other, **spawn)))
+                .fold(None, |best: Option<&Spawn>, spawn| match best {
+                    Some(best) if distance_to_picked(best) >= distance_to_picked(spawn) => {
+                        Some(best)
+                    }
+                    _ => Some(spawn),
+                })
+                .unwrap();
+            picked.push(next);
+        }
+
+        picked
+            .iter()
+            .cycle()
+            .take(count)
+            .map(|spawn| (spawn.x, spawn.y))
+            .collect()
+    }
+}
@@ -0,0 +1,38 @@
+/// SplitMix64. Small, fast and fully deterministic, so that its state can live in the rollback
+/// `sim::State`: every peer draws the same numbers in the same frames, and a rollback rewinds
+/// the generator along with everything else. Bots and `netsim` use it too.
+///
+/// The simulation's generator may only be drawn from while advancing, never while drawing or
+/// predicting effects, or peers end up at different points in the sequence.
+#[derive(Clone, Hash)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`. `n` must not be 0.
+    pub fn below(&mut self, n: u32) -> u32 {
+        (((self.next_u64() >> 32) * n as u64) >> 32) as u32
+    }
+
+    /// Whether an event with a `percent` chance happens.
+    pub fn chance(&mut self, percent: u32) -> bool {
+        self.below(100) < percent
+    }
+}
@@ -0,0 +1,84 @@
+//! Spawn points of Tiled maps and how they are handed out to the players of a match.
+
+use shared::map::MapInfo;
+
+/// A map with an empty 4x1 main layer and a spawn at each of `spawns`, given as the x
+/// coordinate and the spawn's custom properties as JSON.
+fn map(spawns: &[(i32, &str)]) -> MapInfo {
+    let objects: Vec<String> = spawns
+        .iter()
+        .map(|(x, properties)| format!(r#"{{"x":{},"y":0,"properties":[{}]}}"#, x, properties))
+        .collect();
+    let json = format!(
+        concat!(
+            r#"{{"width":4,"tilewidth":8,"layers":["#,
+            r#"{{"name":"main layer","data":[0,0,0,0]}},"#,
+            r#"{{"name":"logic","objects":[{}]}}]}}"#,
+        ),
+        objects.join(",")
+    );
+    MapInfo::parse("test", &json).unwrap()
+}
+
+fn xs(spawns: Vec<(f32, f32)>) -> Vec<f32> {
+    spawns.into_iter().map(|(x, _)| x).collect()
+}
+
+#[test]
+fn higher_priorities_go_first() {
+    let map = map(&[
+        (0, r#"{"name":"priority","type":"int","value":1}"#),
+        (100, ""),
+        (50, r#"{"name":"priority","type":"string","value":"2"}"#),
+    ]);
+    assert_eq!(xs(map.assign_spawns(2)), [50.0, 0.0]);
+    assert_eq!(xs(map.assign_spawns(3)), [50.0, 0.0, 100.0]);
+}
+
+#[test]
+fn spawns_wait_for_enough_players() {
+    let map = map(&[
+        (0, ""),
+        (100, r#"{"name":"min_players","type":"int","value":3}"#),
+        (40, ""),
+    ]);
+    assert_eq!(xs(map.assign_spawns(2)), [0.0, 40.0]);
+    assert_eq!(xs(map.assign_spawns(3)), [0.0, 100.0, 40.0]);
+}
+
+#[test]
+fn spawns_are_far_apart() {
+    let map = map(&[(0, ""), (10, ""), (20, ""), (100, "")]);
+    assert_eq!(xs(map.assign_spawns(2)), [0.0, 100.0]);
+    assert_eq!(xs(map.assign_spawns(3)), [0.0, 100.0, 20.0]);
+}
+
+#[test]
+fn spawns_are_reused_once_all_are_taken() {
+    let map = map(&[(0, ""), (100, "")]);
+    assert_eq!(xs(map.assign_spawns(3)), [0.0, 100.0, 0.0]);
+}
+
+#[test]
+fn invalid_properties_are_reported() {
+    let json = concat!(
+        r#"{"width":1,"tilewidth":8,"layers":[{"name":"main layer","data":[0]},"#,
+        r#"{"name":"logic","objects":[{"x":0,"y":0,"properties":["#,
+        r#"{"name":"priority","type":"bool","value":true}]}]}]}"#,
+    );
+    assert!(MapInfo::parse("test", json).is_err());
+}
+
+#[test]
+fn maps_without_a_size_are_reported() {
+    for header in &[r#""width":0,"tilewidth":8"#, r#""width":1,"tilewidth":0"#] {
+        let json = format!(
+            concat!(
+                r#"{{{},"layers":[{{"name":"main layer","data":[]}},"#,
+                r#"{{"name":"logic","objects":[{{"x":0,"y":0}}]}}]}}"#,
+            ),
+            header
+        );
+        assert!(MapInfo::parse("test", &json).is_err(), "{}", header);
+    }
+}
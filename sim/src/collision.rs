@@ -0,0 +1,128 @@
+//! Tile collision for axis-aligned actors, after `macroquad_platformer`: actors sit on whole
+//! pixels and keep the sub-pixel part of their movement in remainders. Unlike
+//! `macroquad_platformer::World`, an `Actor` is a plain value that lives in `PlayerState`, so
+//! rollbacks save and restore its remainders along with its position.
+
+use crate::Fixed;
+
+/// The solid tiles of a map.
+#[derive(Clone)]
+pub struct TileMap {
+    solid: Vec<bool>,
+    /// In tiles.
+    width: i32,
+    height: i32,
+    /// In pixels.
+    tile_size: i32,
+}
+
+impl TileMap {
+    /// `solid` lists the tiles row by row.
+    pub fn new(solid: Vec<bool>, width: i32, tile_size: i32) -> Self {
+        let height = solid.len() as i32 / width;
+        Self {
+            solid,
+            width,
+            height,
+            tile_size,
+        }
+    }
+
+    /// The solid tiles of a map parsed by `shared::map`.
+    pub fn from_info(map: &shared::map::MapInfo) -> Self {
+        Self::new(map.solid.clone(), map.width, map.tile_size)
+    }
+
+    /// Whether the tile at the given tile coordinates is solid. Everything outside the map is
+    /// empty.
+    fn solid_tile(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return false;
+        }
+        self.solid[(y * self.width + x) as usize]
+    }
+
+    /// Whether the pixel at the given position is in a solid tile.
+    pub fn solid_at(&self, x: Fixed, y: Fixed) -> bool {
+        self.solid_tile(
+            x.floor().div_euclid(self.tile_size),
+            y.floor().div_euclid(self.tile_size),
+        )
+    }
+
+    /// Whether the rectangle overlaps a solid tile.
+    pub fn collides(&self, x: i32, y: i32, width: i32, height: i32) -> bool {
+        let left = x.div_euclid(self.tile_size);
+        let right = (x + width - 1).div_euclid(self.tile_size);
+        let top = y.div_euclid(self.tile_size);
+        let bottom = (y + height - 1).div_euclid(self.tile_size);
+        (top..=bottom).any(|tile_y| (left..=right).any(|tile_x| self.solid_tile(tile_x, tile_y)))
+    }
+}
+
+#[derive(Clone, Debug, Hash)]
+pub struct Actor {
+    pub x: i32,
+    pub y: i32,
+    pub x_remainder: Fixed,
+    pub y_remainder: Fixed,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Actor {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            x_remainder: Fixed::ZERO,
+            y_remainder: Fixed::ZERO,
+            width,
+            height,
+        }
+    }
+
+    /// Whether the actor would overlap a solid tile at the given position.
+    pub fn collides_at(&self, map: &TileMap, x: i32, y: i32) -> bool {
+        map.collides(x, y, self.width, self.height)
+    }
+
+    /// Moves pixel by pixel until the movement is used up or a solid tile is in the way.
+    /// Returns false if the actor hit something.
+    pub fn move_h(&mut self, map: &TileMap, amount: Fixed) -> bool {
+        self.x_remainder += amount;
+        let mut pixels = self.x_remainder.round();
+        if pixels == 0 {
+            return true;
+        }
+        self.x_remainder -= Fixed::from_int(pixels);
+        let sign = pixels.signum();
+        while pixels != 0 {
+            if self.collides_at(map, self.x + sign, self.y) {
+                return false;
+            }
+            self.x += sign;
+            pixels -= sign;
+        }
+        true
+    }
+
+    /// Like `move_h`, vertically.
+    pub fn move_v(&mut self, map: &TileMap, amount: Fixed) -> bool {
+        self.y_remainder += amount;
+        let mut pixels = self.y_remainder.round();
+        if pixels == 0 {
+            return true;
+        }
+        self.y_remainder -= Fixed::from_int(pixels);
+        let sign = pixels.signum();
+        while pixels != 0 {
+            if self.collides_at(map, self.x, self.y + sign) {
+                return false;
+            }
+            self.y += sign;
+            pixels -= sign;
+        }
+        true
+    }
+}
@@ -0,0 +1,117 @@
+use shared::rng::Rng;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+const FRACTION_BITS: u32 = 16;
+
+/// A 16.16 fixed-point number. Integer arithmetic gives the same results on every CPU and on
+/// wasm, which floats only do when nobody is careful. Overflow wraps instead of panicking, so
+/// that a player falling out of the map can not crash debug builds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << FRACTION_BITS);
+
+    pub const fn from_int(value: i32) -> Self {
+        Fixed(value << FRACTION_BITS)
+    }
+
+    pub const fn from_raw(raw: i32) -> Self {
+        Fixed(raw)
+    }
+
+    pub const fn raw(self) -> i32 {
+        self.0
+    }
+
+    /// Exact and platform-independent: scaling by a power of two and rounding are exact float
+    /// operations. Only meant for settings, never for simulation math.
+    pub fn from_f32(value: f32) -> Self {
+        Fixed((value * (1 << FRACTION_BITS) as f32).round() as i32)
+    }
+
+    /// For drawing.
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / (1 << FRACTION_BITS) as f32
+    }
+
+    /// A random number in `-1.0..1.0`.
+    pub fn signed_unit(rng: &mut Rng) -> Self {
+        Fixed::from_raw((rng.next_u64() >> 47) as i32) - Fixed::ONE
+    }
+
+    pub fn floor(self) -> i32 {
+        self.0 >> FRACTION_BITS
+    }
+
+    /// Rounds half away from zero, like `f32::round`.
+    pub fn round(self) -> i32 {
+        let half = 1 << (FRACTION_BITS - 1);
+        if self.0 >= 0 {
+            self.0.wrapping_add(half) >> FRACTION_BITS
+        } else {
+            -(self.0.wrapping_neg().wrapping_add(half) >> FRACTION_BITS)
+        }
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_add(other.0))
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_sub(other.0))
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(self.0.wrapping_neg())
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((self.0 as i64 * other.0 as i64) >> FRACTION_BITS) as i32)
+    }
+}
+
+impl Mul<i32> for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, other: i32) -> Fixed {
+        Fixed(self.0.wrapping_mul(other))
+    }
+}
+
+impl Div<i32> for Fixed {
+    type Output = Fixed;
+
+    fn div(self, other: i32) -> Fixed {
+        Fixed(self.0.wrapping_div(other))
+    }
+}
@@ -0,0 +1,544 @@
+//! The match simulation. It only uses integer and fixed-point math, so that every peer, native
+//! or wasm, computes exactly the same frames from the same inputs.
+
+use bytemuck::{Pod, Zeroable};
+use std::convert::TryInto;
+
+pub use collision::{Actor, TileMap};
+pub use fixed::Fixed;
+pub use shared::rng::Rng;
+
+mod collision;
+mod fixed;
+
+#[macro_use]
+extern crate bitflags;
+
+pub const PLAYER_SIZE: i32 = 8;
+/// `BulletState::owner` of bullets whose shooter left the match.
+pub const NO_OWNER: u32 = u32::MAX;
+
+bitflags! {
+    /// Players who left the game play `Input::empty()` from the frame backroll disconnects
+    /// them on, which every remaining peer agrees on. They stand still until the lobby server
+    /// hands the match off without them.
+    #[repr(C)]
+    #[derive(Zeroable, Pod)]
+    pub struct Input: u8 {
+        const SHOOT = 0b1;
+        const LEFT = 0b10;
+        const RIGHT = 0b100;
+        const JUMP = 0b1000;
+    }
+}
+
+#[derive(Clone, Debug, Hash)]
+pub struct PlayerState {
+    /// Index into `shared::Start`.
+    pub id: u32,
+    pub actor: Actor,
+    pub vx: Fixed,
+    pub vy: Fixed,
+    pub prev_jump_down: bool,
+    pub facing_right: bool,
+    pub health: i32,
+    pub gun_clock: u32,
+    pub kills: u32,
+    /// Index into `shared::MatchSettings::weapons` of the player's gun.
+    pub weapon: u32,
+}
+
+#[derive(Clone, Debug, Hash)]
+pub struct BulletState {
+    /// `PlayerState::id` of the shooter.
+    pub owner: u32,
+    /// The `PlayerState::weapon` it was shot with.
+    pub weapon: u32,
+    pub x: Fixed,
+    pub y: Fixed,
+    pub vx: Fixed,
+    pub vy: Fixed,
+    pub lived: Fixed,
+    pub lifetime: Fixed,
+}
+
+/// Everything that changes during a match, saved and restored on rollbacks.
+#[derive(Clone, Hash)]
+pub struct State {
+    /// Frames simulated so far.
+    pub frame: u32,
+    pub players: Vec<PlayerState>,
+    pub bullets: Vec<BulletState>,
+    /// Kills of each match player by `PlayerState::id`, kept after they are out.
+    pub scoreboard: Vec<u32>,
+    pub rng: Rng,
+}
+
+impl State {
+    /// `shared::content_hash` of `encode`, so that it can be compared across platforms. `Hash`
+    /// can not be, as it may differ with pointer width and endianness.
+    pub fn checksum(&self) -> u64 {
+        shared::content_hash(&self.encode())
+    }
+
+    /// The state in a fixed byte layout, the same on every platform.
+    pub fn encode(&self) -> Vec<u8> {
+        fn fixed(bytes: &mut Vec<u8>, value: Fixed) {
+            bytes.extend_from_slice(&value.raw().to_le_bytes());
+        }
+
+        let mut bytes = self.frame.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(self.players.len() as u32).to_le_bytes());
+        for player in &self.players {
+            bytes.extend_from_slice(&player.id.to_le_bytes());
+            bytes.extend_from_slice(&player.actor.x.to_le_bytes());
+            bytes.extend_from_slice(&player.actor.y.to_le_bytes());
+            fixed(&mut bytes, player.actor.x_remainder);
+            fixed(&mut bytes, player.actor.y_remainder);
+            fixed(&mut bytes, player.vx);
+            fixed(&mut bytes, player.vy);
+            bytes.push(player.prev_jump_down as u8);
+            bytes.push(player.facing_right as u8);
+            bytes.extend_from_slice(&player.health.to_le_bytes());
+            bytes.extend_from_slice(&player.gun_clock.to_le_bytes());
+            bytes.extend_from_slice(&player.kills.to_le_bytes());
+            bytes.extend_from_slice(&player.weapon.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.bullets.len() as u32).to_le_bytes());
+        for bullet in &self.bullets {
+            bytes.extend_from_slice(&bullet.owner.to_le_bytes());
+            bytes.extend_from_slice(&bullet.weapon.to_le_bytes());
+            fixed(&mut bytes, bullet.x);
+            fixed(&mut bytes, bullet.y);
+            fixed(&mut bytes, bullet.vx);
+            fixed(&mut bytes, bullet.vy);
+            fixed(&mut bytes, bullet.lived);
+            fixed(&mut bytes, bullet.lifetime);
+        }
+        bytes.extend_from_slice(&(self.scoreboard.len() as u32).to_le_bytes());
+        for kills in &self.scoreboard {
+            bytes.extend_from_slice(&kills.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.rng.state().to_le_bytes());
+        bytes
+    }
+
+    /// Reads what `encode` wrote. Returns `None` if `bytes` are not an encoded state.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader { bytes };
+        let frame = reader.u32()?;
+        let mut players = vec![];
+        for _ in 0..reader.u32()? {
+            let id = reader.u32()?;
+            let mut actor = Actor::new(reader.i32()?, reader.i32()?, PLAYER_SIZE, PLAYER_SIZE);
+            actor.x_remainder = reader.fixed()?;
+            actor.y_remainder = reader.fixed()?;
+            players.push(PlayerState {
+                id,
+                actor,
+                vx: reader.fixed()?,
+                vy: reader.fixed()?,
+                prev_jump_down: reader.bool()?,
+                facing_right: reader.bool()?,
+                health: reader.i32()?,
+                gun_clock: reader.u32()?,
+                kills: reader.u32()?,
+                weapon: reader.u32()?,
+            });
+        }
+        let mut bullets = vec![];
+        for _ in 0..reader.u32()? {
+            bullets.push(BulletState {
+                owner: reader.u32()?,
+                weapon: reader.u32()?,
+                x: reader.fixed()?,
+                y: reader.fixed()?,
+                vx: reader.fixed()?,
+                vy: reader.fixed()?,
+                lived: reader.fixed()?,
+                lifetime: reader.fixed()?,
+            });
+        }
+        let mut scoreboard = vec![];
+        for _ in 0..reader.u32()? {
+            scoreboard.push(reader.u32()?);
+        }
+        // The generator's state is all there is to it, like a seed.
+        let rng = Rng::new(reader.u64()?);
+        if !reader.bytes.is_empty() {
+            return None;
+        }
+        Some(Self {
+            frame,
+            players,
+            bullets,
+            scoreboard,
+            rng,
+        })
+    }
+}
+
+/// Reads the little-endian fields of `State::encode` in order.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn fixed(&mut self) -> Option<Fixed> {
+        Some(Fixed::from_raw(self.i32()?))
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.take(1)?[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// `shared::Rules` converted to fixed point.
+struct Rules {
+    gravity: Fixed,
+    jump_speed: Fixed,
+    run_speed: Fixed,
+    max_health: i32,
+}
+
+/// A player at their spawn point, with a random one of `weapon_count` weapons.
+fn new_player(
+    id: u32,
+    (x, y): (i32, i32),
+    rules: &Rules,
+    weapon_count: usize,
+    rng: &mut Rng,
+) -> PlayerState {
+    PlayerState {
+        id,
+        actor: Actor::new(x, y, PLAYER_SIZE, PLAYER_SIZE),
+        vx: Fixed::ZERO,
+        vy: Fixed::ZERO,
+        prev_jump_down: false,
+        facing_right: true,
+        health: rules.max_health,
+        gun_clock: 0,
+        kills: 0,
+        weapon: rng.below(weapon_count as u32),
+    }
+}
+
+/// `shared::Weapon` converted to fixed point.
+struct Weapon {
+    bullet_speed: Fixed,
+    bullet_lifetime: Fixed,
+    interval_ticks: u32,
+    damage: i32,
+    spread: Fixed,
+    crit_chance: u32,
+}
+
+pub struct Sim {
+    map: TileMap,
+    rules: Rules,
+    weapons: Vec<Weapon>,
+    /// Seconds per tick.
+    timestep: Fixed,
+    score_limit: u32,
+    /// The most players the match had at once. Matches that never had two, like practicing
+    /// alone, never end.
+    match_size: usize,
+    pub state: State,
+}
+
+impl Sim {
+    /// `spawns` has the position of each player of the match, in `shared::Start` order. Fails
+    /// if the settings can not be played, e.g. without weapons.
+    pub fn new(
+        settings: &shared::MatchSettings,
+        map: TileMap,
+        spawns: &[(i32, i32)],
+    ) -> Result<Self, String> {
+        if settings.weapons.is_empty() {
+            return Err("The match has no weapons".to_string());
+        }
+        let rules = Rules {
+            gravity: Fixed::from_f32(settings.rules.gravity),
+            jump_speed: Fixed::from_f32(settings.rules.jump_speed),
+            run_speed: Fixed::from_f32(settings.rules.run_speed),
+            max_health: settings.rules.max_health,
+        };
+        let mut rng = Rng::new(settings.seed);
+        let players = spawns
+            .iter()
+            .enumerate()
+            .map(|(id, spawn)| {
+                new_player(id as u32, *spawn, &rules, settings.weapons.len(), &mut rng)
+            })
+            .collect();
+        Ok(Self {
+            map,
+            rules,
+            weapons: settings
+                .weapons
+                .iter()
+                .map(|weapon| Weapon {
+                    bullet_speed: Fixed::from_f32(weapon.bullet_speed),
+                    bullet_lifetime: Fixed::from_f32(weapon.bullet_lifetime),
+                    interval_ticks: weapon.interval_ticks.max(1),
+                    damage: weapon.damage,
+                    spread: Fixed::from_f32(weapon.spread),
+                    crit_chance: weapon.crit_chance,
+                })
+                .collect(),
+            timestep: Fixed::ONE / settings.tick_rate.max(1) as i32,
+            score_limit: settings.score_limit,
+            match_size: spawns.len(),
+            state: State {
+                frame: 0,
+                players,
+                bullets: vec![],
+                scoreboard: vec![0; spawns.len()],
+                rng,
+            },
+        })
+    }
+
+    pub fn map(&self) -> &TileMap {
+        &self.map
+    }
+
+    /// Everything needed to resume the match elsewhere with `restore`, for a `Sim` made with
+    /// the same settings and map.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = (self.match_size as u32).to_le_bytes().to_vec();
+        bytes.extend(self.state.encode());
+        bytes
+    }
+
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        let invalid = || "Invalid snapshot".to_string();
+        let mut reader = Reader { bytes: snapshot };
+        let match_size = reader.u32().ok_or_else(invalid)?;
+        let state = State::decode(reader.bytes).ok_or_else(invalid)?;
+        let weapons = state.players.iter().map(|player| player.weapon);
+        if weapons
+            .chain(state.bullets.iter().map(|bullet| bullet.weapon))
+            .any(|weapon| weapon as usize >= self.weapons.len())
+        {
+            return Err("The snapshot has weapons the match does not".to_string());
+        }
+        self.state = state;
+        self.match_size = match_size as usize;
+        Ok(())
+    }
+
+    /// Carries the match over to a new roster. `roster` has, for each player of the new
+    /// roster, their `PlayerState::id` so far, or `None` for players who join the match at
+    /// their entry of `spawns`. Players missing from `roster` leave the match.
+    pub fn change_roster(&mut self, roster: &[Option<u32>], spawns: &[(i32, i32)]) {
+        let old_players = std::mem::take(&mut self.state.players);
+        for (id, (old_id, spawn)) in roster.iter().zip(spawns).enumerate() {
+            let id = id as u32;
+            match old_id {
+                // Players that were out stay out.
+                Some(old_id) => {
+                    if let Some(player) = old_players.iter().find(|player| player.id == *old_id) {
+                        self.state.players.push(PlayerState {
+                            id,
+                            ..player.clone()
+                        });
+                    }
+                }
+                None => self.state.players.push(new_player(
+                    id,
+                    *spawn,
+                    &self.rules,
+                    self.weapons.len(),
+                    &mut self.state.rng,
+                )),
+            }
+        }
+        self.match_size = self.match_size.max(roster.len());
+        let old_scoreboard = std::mem::take(&mut self.state.scoreboard);
+        self.state.scoreboard = roster
+            .iter()
+            .map(|old_id| old_id.and_then(|old_id| old_scoreboard.get(old_id as usize)))
+            .map(|kills| kills.copied().unwrap_or(0))
+            .collect();
+        for bullet in &mut self.state.bullets {
+            bullet.owner = roster
+                .iter()
+                .position(|old_id| *old_id == Some(bullet.owner))
+                .map_or(NO_OWNER, |id| id as u32);
+        }
+    }
+
+    /// Simulates one tick with each player's input, looked up by `PlayerState::id`. Returns
+    /// where bullets hit something.
+    pub fn advance(&mut self, input: impl Fn(u32) -> Input) -> Vec<(Fixed, Fixed)> {
+        let map = &self.map;
+        let rules = &self.rules;
+        let weapons = &self.weapons;
+        let timestep = self.timestep;
+        let state = &mut self.state;
+        let mut hits = vec![];
+
+        for player in &mut state.players {
+            let on_ground = player
+                .actor
+                .collides_at(map, player.actor.x, player.actor.y + 1);
+            let player_input = input(player.id);
+
+            if player_input.contains(Input::RIGHT) {
+                player.vx = rules.run_speed;
+            } else if player_input.contains(Input::LEFT) {
+                player.vx = -rules.run_speed;
+            } else {
+                player.vx = Fixed::ZERO;
+            }
+            if player_input.contains(Input::JUMP) && !player.prev_jump_down && on_ground {
+                player.vy = -rules.jump_speed;
+            }
+            player.prev_jump_down = player_input.contains(Input::JUMP);
+            if player_input.contains(Input::SHOOT) {
+                let weapon = &weapons[player.weapon as usize];
+                if player.gun_clock == 0 {
+                    let dir = if player.facing_right { 1 } else { -1 };
+                    let vy = if weapon.spread > Fixed::ZERO {
+                        weapon.bullet_speed * weapon.spread * Fixed::signed_unit(&mut state.rng)
+                    } else {
+                        Fixed::ZERO
+                    };
+                    state.bullets.push(BulletState {
+                        owner: player.id,
+                        weapon: player.weapon,
+                        x: Fixed::from_int(player.actor.x + PLAYER_SIZE / 2 + dir * 8),
+                        y: Fixed::from_int(player.actor.y + PLAYER_SIZE / 2),
+                        vx: weapon.bullet_speed * dir,
+                        vy,
+                        lived: Fixed::ZERO,
+                        lifetime: weapon.bullet_lifetime,
+                    });
+                }
+                player.gun_clock += 1;
+                player.gun_clock %= weapon.interval_ticks;
+            } else {
+                player.gun_clock = 0;
+            }
+
+            if player.vx < Fixed::ZERO {
+                player.facing_right = false;
+            }
+            if player.vx > Fixed::ZERO {
+                player.facing_right = true;
+            }
+
+            if !on_ground {
+                player.vy += rules.gravity * timestep;
+            }
+
+            player.actor.move_h(map, player.vx * timestep);
+            if !player.actor.move_v(map, player.vy * timestep) {
+                player.vy = Fixed::ZERO;
+            }
+        }
+
+        for bullet in &mut state.bullets {
+            bullet.x += bullet.vx * timestep;
+            bullet.y += bullet.vy * timestep;
+            bullet.lived += timestep;
+        }
+        let players = &mut state.players;
+        let rng = &mut state.rng;
+        let mut killers = vec![];
+        state.bullets.retain(|bullet| {
+            if map.solid_at(bullet.x, bullet.y) {
+                hits.push((bullet.x, bullet.y));
+                return false;
+            }
+            for player in players.iter_mut() {
+                let left = Fixed::from_int(player.actor.x);
+                let top = Fixed::from_int(player.actor.y);
+                let size = Fixed::from_int(PLAYER_SIZE);
+                if bullet.x >= left
+                    && bullet.x < left + size
+                    && bullet.y >= top
+                    && bullet.y < top + size
+                {
+                    let weapon = &weapons[bullet.weapon as usize];
+                    let damage = if weapon.crit_chance > 0 && rng.chance(weapon.crit_chance) {
+                        weapon.damage * 2
+                    } else {
+                        weapon.damage
+                    };
+                    if player.health > 0 && player.health <= damage {
+                        killers.push(bullet.owner);
+                    }
+                    player.health -= damage;
+                    hits.push((bullet.x, bullet.y));
+                    return false;
+                }
+            }
+            bullet.lived < bullet.lifetime
+        });
+        for killer in killers {
+            if let Some(player) = players.iter_mut().find(|player| player.id == killer) {
+                player.kills += 1;
+                state.scoreboard[killer as usize] += 1;
+            }
+        }
+
+        players.retain(|player| player.health > 0);
+        state.frame += 1;
+        hits
+    }
+
+    /// What to tell the lobby server once the match is over.
+    pub fn report(&self) -> shared::MatchReport {
+        shared::MatchReport {
+            winner: self.outcome().flatten(),
+            kills: self.state.scoreboard.clone(),
+            frame: self.state.frame,
+            state_hash: self.state.checksum(),
+        }
+    }
+
+    /// Once a player reaches the score limit or at most one player is left standing, the match
+    /// is over and this returns the `shared::Start` index of the winner, if there is one.
+    pub fn outcome(&self) -> Option<Option<u32>> {
+        let score_limit = self.score_limit;
+        if let Some(player) = self
+            .state
+            .players
+            .iter()
+            .find(|player| score_limit > 0 && player.kills >= score_limit)
+        {
+            return Some(Some(player.id));
+        }
+        if self.match_size < 2 || self.state.players.len() > 1 {
+            return None;
+        }
+        Some(self.state.players.first().map(|player| player.id))
+    }
+}
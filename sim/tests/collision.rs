@@ -0,0 +1,56 @@
+use sim::{Actor, Fixed, TileMap};
+
+/// An empty 4x4 room with a solid floor.
+fn map() -> TileMap {
+    let mut solid = vec![false; 16];
+    for tile in &mut solid[12..] {
+        *tile = true;
+    }
+    TileMap::new(solid, 4, 8)
+}
+
+#[test]
+fn floor_stops_falling() {
+    let map = map();
+    let mut actor = Actor::new(0, 0, 8, 8);
+    assert!(!actor.move_v(&map, Fixed::from_int(20)));
+    assert_eq!(actor.y, 16);
+}
+
+#[test]
+fn slow_movement_keeps_sub_pixels() {
+    let map = map();
+    let mut actor = Actor::new(0, 0, 8, 8);
+    let quarter = Fixed::ONE / 4;
+    for _ in 0..8 {
+        assert!(actor.move_h(&map, quarter));
+    }
+    assert_eq!(actor.x, 2);
+}
+
+#[test]
+fn restored_actor_moves_like_the_original() {
+    let map = map();
+    let mut actor = Actor::new(0, 0, 8, 8);
+    let step = Fixed::from_f32(0.3);
+    actor.move_h(&map, step);
+    actor.move_v(&map, step);
+
+    let mut restored = actor.clone();
+    for _ in 0..20 {
+        actor.move_h(&map, step);
+        actor.move_v(&map, step);
+        restored.move_h(&map, step);
+        restored.move_v(&map, step);
+    }
+    assert_eq!(
+        (actor.x, actor.y, actor.x_remainder, actor.y_remainder),
+        (
+            restored.x,
+            restored.y,
+            restored.x_remainder,
+            restored.y_remainder
+        )
+    );
+    assert_eq!((actor.x, actor.y), (6, 6));
+}
@@ -0,0 +1,253 @@
+//! Plays a scripted match and compares the final state with a checksum recorded on x86_64.
+//! Every target has to reproduce it, so run this on wasm as well, e.g. with
+//! `cargo test -p sim --target wasm32-wasi`, which uses the runner from `.cargo/config.toml`.
+
+use sim::{Fixed, Input, Sim, TileMap};
+
+/// `State::checksum` after `FRAMES` frames of `script`.
+const EXPECTED_CHECKSUM: u64 = 2934548421122770334;
+const FRAMES: u32 = 3000;
+
+/// A 40x19 room with a floor, walls and two platforms.
+fn map() -> TileMap {
+    let (width, height) = (40, 19);
+    let mut solid = vec![false; width * height];
+    for x in 0..width {
+        solid[(height - 1) * width + x] = true;
+    }
+    for y in 0..height {
+        solid[y * width] = true;
+        solid[y * width + width - 1] = true;
+    }
+    for x in 8..16 {
+        solid[13 * width + x] = true;
+    }
+    for x in 24..32 {
+        solid[9 * width + x] = true;
+    }
+    TileMap::new(solid, width as i32, 8)
+}
+
+fn settings() -> shared::MatchSettings {
+    shared::MatchSettings {
+        seed: 1,
+        map: "test".to_string(),
+        map_hash: 0,
+        tick_rate: 60,
+        rules: shared::Rules::default(),
+        // Exercise the random number generator too.
+        weapons: vec![
+            shared::Weapon {
+                spread: 0.1,
+                crit_chance: 20,
+                ..shared::Weapon::pistol()
+            },
+            shared::Weapon {
+                bullet_speed: 200.0,
+                interval_ticks: 4,
+                damage: 2,
+                spread: 0.3,
+                ..shared::Weapon::pistol()
+            },
+        ],
+        score_limit: 0,
+    }
+}
+
+/// Deterministic but busy inputs: players run back and forth, jump and shoot at different
+/// rhythms.
+fn script(frame: u32, player: u32) -> Input {
+    let mut input = Input::empty();
+    let phase = frame / (40 + player * 13);
+    match phase % 3 {
+        0 => input |= Input::RIGHT,
+        1 => input |= Input::LEFT,
+        _ => {}
+    }
+    if (frame + player * 7) % 50 < 10 {
+        input |= Input::JUMP;
+    }
+    if (frame + player * 11) % 30 < 20 {
+        input |= Input::SHOOT;
+    }
+    input
+}
+
+#[test]
+fn scripted_match_checksum() {
+    let mut sim = Sim::new(
+        &settings(),
+        map(),
+        &[(16, 100), (296, 100), (96, 60), (208, 40)],
+    )
+    .unwrap();
+    for frame in 0..FRAMES {
+        sim.advance(|player| script(frame, player));
+    }
+    assert_eq!(sim.state.checksum(), EXPECTED_CHECKSUM);
+    for player in &sim.state.players {
+        assert_eq!(sim.state.scoreboard[player.id as usize], player.kills);
+    }
+}
+
+#[test]
+fn rollback_replays_identically() {
+    let mut sim = Sim::new(&settings(), map(), &[(16, 100), (296, 100)]).unwrap();
+    for frame in 0..500 {
+        sim.advance(|player| script(frame, player));
+    }
+    let saved = sim.state.clone();
+    let checksum = saved.checksum();
+    for frame in 500..600 {
+        sim.advance(|player| script(frame, player));
+    }
+    let ahead = sim.state.checksum();
+
+    sim.state = saved;
+    assert_eq!(sim.state.checksum(), checksum);
+    for frame in 500..600 {
+        sim.advance(|player| script(frame, player));
+    }
+    assert_eq!(sim.state.checksum(), ahead);
+}
+
+#[test]
+fn snapshot_resumes_identically() {
+    let mut sim = Sim::new(&settings(), map(), &[(16, 100), (296, 100)]).unwrap();
+    for frame in 0..500 {
+        sim.advance(|player| script(frame, player));
+    }
+    // Spawns do not matter, the snapshot has everyone's position.
+    let mut resumed = Sim::new(&settings(), map(), &[(0, 0), (0, 0)]).unwrap();
+    resumed.restore(&sim.snapshot()).unwrap();
+    assert_eq!(resumed.state.checksum(), sim.state.checksum());
+    for frame in 500..600 {
+        sim.advance(|player| script(frame, player));
+        resumed.advance(|player| script(frame, player));
+    }
+    assert_eq!(resumed.state.checksum(), sim.state.checksum());
+
+    assert!(resumed.restore(&[1, 2, 3]).is_err());
+}
+
+/// Saves state while the players are between pixels, as rollbacks do every few frames.
+#[test]
+fn restored_state_keeps_sub_pixel_movement() {
+    // Player 0 runs right, player 1 falls from high up.
+    let input = |player: u32| {
+        if player == 0 {
+            Input::RIGHT
+        } else {
+            Input::empty()
+        }
+    };
+    let positions = |sim: &Sim| -> Vec<(i32, i32)> {
+        let players = &sim.state.players;
+        players
+            .iter()
+            .map(|player| (player.actor.x, player.actor.y))
+            .collect()
+    };
+    let mut sim = Sim::new(&settings(), map(), &[(16, 100), (296, 8)]).unwrap();
+    for _ in 0..3 {
+        sim.advance(input);
+    }
+    let players = &sim.state.players;
+    assert_ne!(players[0].actor.x_remainder, Fixed::ZERO);
+    assert_ne!(players[1].actor.y_remainder, Fixed::ZERO);
+
+    let saved = sim.state.clone();
+    let mut resumed = Sim::new(&settings(), map(), &[(0, 0), (0, 0)]).unwrap();
+    resumed.restore(&sim.snapshot()).unwrap();
+    let mut ahead = vec![];
+    for _ in 0..20 {
+        sim.advance(input);
+        ahead.push(positions(&sim));
+    }
+
+    sim.state = saved;
+    for expected in &ahead {
+        sim.advance(input);
+        resumed.advance(input);
+        assert_eq!(&positions(&sim), expected);
+        assert_eq!(&positions(&resumed), expected);
+    }
+}
+
+#[test]
+fn roster_change_keeps_players_that_stay() {
+    let mut sim = Sim::new(&settings(), map(), &[(16, 100), (296, 100), (96, 60)]).unwrap();
+    for frame in 0..200 {
+        sim.advance(|player| script(frame, player));
+    }
+    let before = sim.state.clone();
+
+    // Player 1 leaves, player 2 becomes player 0, and someone joins as player 1.
+    sim.change_roster(&[Some(2), None, Some(0)], &[(0, 0), (208, 40), (0, 0)]);
+    let ids: Vec<u32> = sim.state.players.iter().map(|player| player.id).collect();
+    assert_eq!(ids, [0, 1, 2]);
+    assert_eq!(sim.state.players[0].actor.x, before.players[2].actor.x);
+    assert_eq!(sim.state.players[0].kills, before.players[2].kills);
+    assert_eq!(
+        sim.state.scoreboard,
+        [before.scoreboard[2], 0, before.scoreboard[0]]
+    );
+    assert_eq!(
+        (sim.state.players[1].actor.x, sim.state.players[1].actor.y),
+        (208, 40)
+    );
+    assert_eq!(
+        sim.state.players[1].health,
+        shared::Rules::default().max_health
+    );
+    for (bullet, old) in sim.state.bullets.iter().zip(&before.bullets) {
+        let expected = match old.owner {
+            0 => 2,
+            1 => sim::NO_OWNER,
+            _ => 0,
+        };
+        assert_eq!(bullet.owner, expected);
+    }
+}
+
+#[test]
+fn matches_joined_after_the_start_can_end() {
+    let mut sim = Sim::new(&settings(), map(), &[(16, 100)]).unwrap();
+    assert_eq!(sim.outcome(), None);
+
+    // Someone joins, then the first player leaves.
+    sim.change_roster(&[Some(0), None], &[(0, 0), (296, 100)]);
+    assert_eq!(sim.outcome(), None);
+    sim.change_roster(&[Some(1)], &[(0, 0)]);
+    assert_eq!(sim.outcome(), Some(Some(0)));
+}
+
+#[test]
+fn players_spawn_with_weapons_of_the_set() {
+    let sim = Sim::new(&settings(), map(), &[(16, 100); 8]).unwrap();
+    let weapons: Vec<u32> = sim
+        .state
+        .players
+        .iter()
+        .map(|player| player.weapon)
+        .collect();
+    assert!(weapons.iter().all(|weapon| *weapon < 2));
+    assert!(weapons.contains(&0) && weapons.contains(&1));
+
+    // Resuming only works with the weapons the snapshot was taken with.
+    let pistol_only = shared::MatchSettings {
+        weapons: vec![shared::Weapon::pistol()],
+        ..settings()
+    };
+    let mut resumed = Sim::new(&pistol_only, map(), &[(0, 0); 8]).unwrap();
+    assert!(resumed.restore(&sim.snapshot()).is_err());
+}
+
+#[test]
+fn refuses_a_match_without_weapons() {
+    let settings = shared::MatchSettings {
+        weapons: vec![],
+        ..settings()
+    };
+    assert!(Sim::new(&settings, map(), &[(16, 100), (296, 100)]).is_err());
+}